@@ -10,6 +10,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .visibility(lrpar::Visibility::Public)
         })
         .lexer_in_src_dir("lib/ukiyo.l")?
+        // String literals are lexed by `lexer.rs`, so their tokens are only used by the grammar.
+        .allow_missing_terms_in_lexer(true)
         .visibility(lrlex::Visibility::Public)
         .build()?;
     Ok(())
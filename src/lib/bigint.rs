@@ -0,0 +1,270 @@
+use std::{cmp::Ordering, fmt};
+
+/// The base of each digit of a `BigInt`'s magnitude.
+const BASE: u64 = 1 << 32;
+
+/// An arbitrary-precision integer, stored as a sign and a magnitude of base 2^32 digits, least
+/// significant first. The magnitude never has leading zero digits, and zero is never negative, so
+/// each value has exactly one representation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    mag: Vec<u32>,
+}
+
+impl BigInt {
+    fn new(negative: bool, mut mag: Vec<u32>) -> Self {
+        while mag.last() == Some(&0) {
+            mag.pop();
+        }
+        let negative = negative && !mag.is_empty();
+        BigInt { negative, mag }
+    }
+
+    /// Parse a string of decimal digits, optionally preceded by `-`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        if digits.is_empty() {
+            return None;
+        }
+        let mut mag = Vec::new();
+        for c in digits.chars() {
+            let digit = c.to_digit(10)?;
+            mag_mul_small(&mut mag, 10);
+            mag_add_small(&mut mag, digit);
+        }
+        Some(BigInt::new(negative, mag))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    /// Convert to an `i64`, if the value fits in one.
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+        let mag = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &d| (acc << 32) | u64::from(d));
+        if self.negative {
+            0i64.checked_sub_unsigned(mag)
+        } else {
+            i64::try_from(mag).ok()
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let mag = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0.0, |acc, &d| acc * BASE as f64 + f64::from(d));
+        if self.negative {
+            -mag
+        } else {
+            mag
+        }
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, mag_add(&self.mag, &other.mag));
+        }
+        // The signs differ, so subtract the smaller magnitude from the larger.
+        match mag_cmp(&self.mag, &other.mag) {
+            Ordering::Less => BigInt::new(other.negative, mag_sub(&other.mag, &self.mag)),
+            _ => BigInt::new(self.negative, mag_sub(&self.mag, &other.mag)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        let mut mag = vec![0u32; self.mag.len() + other.mag.len()];
+        for (i, &x) in self.mag.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &y) in other.mag.iter().enumerate() {
+                let cur = u64::from(mag[i + j]) + u64::from(x) * u64::from(y) + carry;
+                mag[i + j] = cur as u32;
+                carry = cur >> 32;
+            }
+            mag[i + other.mag.len()] = carry as u32;
+        }
+        BigInt::new(self.negative != other.negative, mag)
+    }
+
+    /// Divide by `other`, rounding towards zero, returning the quotient and remainder. The
+    /// remainder takes the sign of `self`, as with Rust's integer division. `other` must not be
+    /// zero.
+    pub fn div_rem(&self, other: &BigInt) -> (BigInt, BigInt) {
+        debug_assert!(!other.is_zero());
+        // Shift-and-subtract long division, one bit of the quotient at a time.
+        let mut quot = vec![0u32; self.mag.len()];
+        let mut rem = Vec::new();
+        for i in (0..self.mag.len() * 32).rev() {
+            mag_shl1(&mut rem);
+            if (self.mag[i / 32] >> (i % 32)) & 1 == 1 {
+                mag_add_small(&mut rem, 1);
+            }
+            if mag_cmp(&rem, &other.mag) != Ordering::Less {
+                rem = mag_sub(&rem, &other.mag);
+                quot[i / 32] |= 1 << (i % 32);
+            }
+        }
+        (
+            BigInt::new(self.negative != other.negative, quot),
+            BigInt::new(self.negative, rem),
+        )
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::new(!self.negative, self.mag.clone())
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(x: i64) -> Self {
+        let mag = x.unsigned_abs();
+        BigInt::new(x < 0, vec![mag as u32, (mag >> 32) as u32])
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => mag_cmp(&self.mag, &other.mag),
+            (true, true) => mag_cmp(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // Split the magnitude into base 10^9 chunks, least significant first.
+        let mut chunks = Vec::new();
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            chunks.push(mag_div_small(&mut mag, 1_000_000_000));
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn mag_cmp(lhs: &[u32], rhs: &[u32]) -> Ordering {
+    lhs.len()
+        .cmp(&rhs.len())
+        .then_with(|| lhs.iter().rev().cmp(rhs.iter().rev()))
+}
+
+fn mag_add(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let mut mag = Vec::with_capacity(lhs.len().max(rhs.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..lhs.len().max(rhs.len()) {
+        let cur =
+            u64::from(*lhs.get(i).unwrap_or(&0)) + u64::from(*rhs.get(i).unwrap_or(&0)) + carry;
+        mag.push(cur as u32);
+        carry = cur >> 32;
+    }
+    mag.push(carry as u32);
+    mag
+}
+
+/// Subtract `rhs` from `lhs`, whose magnitude must be at least as large.
+fn mag_sub(lhs: &[u32], rhs: &[u32]) -> Vec<u32> {
+    let mut mag = Vec::with_capacity(lhs.len());
+    let mut borrow = 0i64;
+    for (i, &x) in lhs.iter().enumerate() {
+        let mut cur = i64::from(x) - i64::from(*rhs.get(i).unwrap_or(&0)) - borrow;
+        borrow = 0;
+        if cur < 0 {
+            cur += BASE as i64;
+            borrow = 1;
+        }
+        mag.push(cur as u32);
+    }
+    debug_assert_eq!(borrow, 0);
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    mag
+}
+
+fn mag_add_small(mag: &mut Vec<u32>, x: u32) {
+    let mut carry = u64::from(x);
+    for d in mag.iter_mut() {
+        if carry == 0 {
+            return;
+        }
+        let cur = u64::from(*d) + carry;
+        *d = cur as u32;
+        carry = cur >> 32;
+    }
+    if carry != 0 {
+        mag.push(carry as u32);
+    }
+}
+
+fn mag_mul_small(mag: &mut Vec<u32>, x: u32) {
+    let mut carry = 0u64;
+    for d in mag.iter_mut() {
+        let cur = u64::from(*d) * u64::from(x) + carry;
+        *d = cur as u32;
+        carry = cur >> 32;
+    }
+    if carry != 0 {
+        mag.push(carry as u32);
+    }
+}
+
+/// Divide `mag` in place by `x`, returning the remainder.
+fn mag_div_small(mag: &mut Vec<u32>, x: u32) -> u32 {
+    let mut rem = 0u64;
+    for d in mag.iter_mut().rev() {
+        let cur = (rem << 32) | u64::from(*d);
+        *d = (cur / u64::from(x)) as u32;
+        rem = cur % u64::from(x);
+    }
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    rem as u32
+}
+
+fn mag_shl1(mag: &mut Vec<u32>) {
+    let mut carry = 0;
+    for d in mag.iter_mut() {
+        let next_carry = *d >> 31;
+        *d = (*d << 1) | carry;
+        carry = next_carry;
+    }
+    if carry != 0 {
+        mag.push(carry);
+    }
+}
@@ -1,144 +1,675 @@
-use crate::config_ast::{self};
+use crate::{
+    bigint::BigInt,
+    config_ast::{self},
+};
 use lrlex::DefaultLexeme;
-use lrpar::NonStreamingLexer;
-use std::fmt::{self};
+use lrpar::{NonStreamingLexer, Span};
+use std::{
+    collections::HashSet,
+    fmt::{self},
+    ops::{Index, IndexMut},
+    rc::Rc,
+};
 pub type Ast = Vec<config_ast::Expr>;
 
 #[derive(Debug, Clone)]
 pub enum CallTarget {
-    Func(String, usize),
     Var(usize, usize),
-    Builtins(String),
+    Upvalue(usize, usize),
+    Global(String, usize),
+    Builtins(String, usize),
+    /// The value beneath the arguments on the stack.
+    Value(usize),
+}
+
+/// The functions provided by the VM, callable by name unless shadowed by a variable.
+pub const BUILTINS: &[&str] = &[
+    "len", "push", "pop", "keys", "values", "has", "substr", "split", "join", "upper", "lower",
+    "contains", "trim",
+];
+
+/// Where a closure finds a captured variable when it is created: either in a local of the
+/// enclosing function, or in one of the enclosing function's own captured variables.
+#[derive(Debug, Clone)]
+pub enum Capture {
+    Local(usize),
+    Upvalue(usize),
 }
 #[derive(Debug, Clone)]
 pub enum OpCode {
-    PushInt(i32),
+    PushInt(i64),
+    PushBigInt(Rc<BigInt>),
+    PushFloat(f64),
     PushStr(String),
+    PushBool(bool),
+    PushNone,
+    /// Push the placeholder for the local `name` before its `let` has run.
+    PushUndefined(String),
     Plus,
     Minus,
+    Mul,
+    Div,
+    Mod,
     Eqeq,
+    Noteq,
     Lteq,
     Lt,
+    Gteq,
+    Gt,
+    Not,
+    Neg,
+    DefineVar(usize),
     StoreVar(usize),
     LoadVar(usize),
+    StoreUpvalue(usize),
+    LoadUpvalue(usize),
+    StoreGlobal(String),
+    LoadGlobal(String),
+    ToString,
+    /// Convert the value on top of the stack into a list to be iterated over.
+    ToList,
+    /// Check that the value on top of the stack is an int, for use as a range bound.
+    CheckInt,
+    Concat(usize),
+    BuildList(usize),
+    BuildMap(usize),
+    LoadIndex,
+    StoreIndex,
     Call(CallTarget),
     Jump(usize),
     JumpIfFalse(usize),
+    JumpIfFalseOrPop(usize),
+    JumpIfTrueOrPop(usize),
     Return,
-    DefineFunc(String, Vec<String>, Vec<OpCode>, Vec<String>),
-    InlineFunc(Vec<String>, Vec<OpCode>, Vec<String>),
-    Patch,
+    Pop,
+    DefineFunc(String, Vec<String>, Rc<Bytecode>),
+    InlineFunc(Option<String>, Vec<String>, Rc<Bytecode>, Vec<Capture>),
 }
 
 impl fmt::Display for OpCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             OpCode::PushInt(i) => write!(f, "PushInt({})", i),
+            OpCode::PushBigInt(i) => write!(f, "PushBigInt({})", i),
+            OpCode::PushFloat(x) => write!(f, "PushFloat({:?})", x),
             OpCode::PushStr(s) => write!(f, "PushStr({})", s),
+            OpCode::PushBool(b) => write!(f, "PushBool({})", b),
+            OpCode::PushNone => write!(f, "PushNone"),
+            OpCode::PushUndefined(s) => write!(f, "PushUndefined({})", s),
             OpCode::Plus => write!(f, "Plus"),
             OpCode::Minus => write!(f, "Minus"),
+            OpCode::Mul => write!(f, "Mul"),
+            OpCode::Div => write!(f, "Div"),
+            OpCode::Mod => write!(f, "Mod"),
             OpCode::Eqeq => write!(f, "Eqeq"),
             OpCode::Lteq => write!(f, "Lteq"),
             OpCode::Lt => write!(f, "Lt"),
+            OpCode::Noteq => write!(f, "Noteq"),
+            OpCode::Gteq => write!(f, "Gteq"),
+            OpCode::Gt => write!(f, "Gt"),
+            OpCode::Not => write!(f, "Not"),
+            OpCode::Neg => write!(f, "Neg"),
+            OpCode::DefineVar(i) => write!(f, "DefineVar({})", i),
             OpCode::StoreVar(i) => write!(f, "StoreVar({})", i),
             OpCode::LoadVar(i) => write!(f, "LoadVar({})", i),
+            OpCode::StoreUpvalue(i) => write!(f, "StoreUpvalue({})", i),
+            OpCode::LoadUpvalue(i) => write!(f, "LoadUpvalue({})", i),
+            OpCode::StoreGlobal(s) => write!(f, "StoreGlobal({})", s),
+            OpCode::LoadGlobal(s) => write!(f, "LoadGlobal({})", s),
+            OpCode::ToString => write!(f, "ToString"),
+            OpCode::ToList => write!(f, "ToList"),
+            OpCode::CheckInt => write!(f, "CheckInt"),
+            OpCode::Concat(n) => write!(f, "Concat({})", n),
+            OpCode::BuildList(n) => write!(f, "BuildList({})", n),
+            OpCode::BuildMap(n) => write!(f, "BuildMap({})", n),
+            OpCode::LoadIndex => write!(f, "LoadIndex"),
+            OpCode::StoreIndex => write!(f, "StoreIndex"),
             OpCode::Call(s) => write!(f, "Call({:?})", s),
             OpCode::Jump(i) => write!(f, "Jump({})", i),
             OpCode::JumpIfFalse(i) => write!(f, "JumpIfFalse({})", i),
+            OpCode::JumpIfFalseOrPop(i) => write!(f, "JumpIfFalseOrPop({})", i),
+            OpCode::JumpIfTrueOrPop(i) => write!(f, "JumpIfTrueOrPop({})", i),
             OpCode::Return => write!(f, "Return"),
-            OpCode::DefineFunc(s, ops1, ops2, ops3) => {
-                write!(f, "DefineFunc({}, {:?}, {:?}, {:?})", s, ops1, ops2, ops3)
+            OpCode::Pop => write!(f, "Pop"),
+            OpCode::DefineFunc(s, ops1, ops2) => {
+                write!(f, "DefineFunc({}, {:?}, {:?})", s, ops1, ops2)
+            }
+            OpCode::InlineFunc(ops1, ops2, ops3, ops4) => {
+                write!(
+                    f,
+                    "InlineFunc({:?}, {:?}, {:?}, {:?})",
+                    ops1, ops2, ops3, ops4
+                )
             }
-            OpCode::Patch => write!(f, "Patch"),
-            OpCode::InlineFunc(_, _, _) => todo!(),
         }
     }
 }
+
+/// A sequence of opcodes, each paired with the span of the expression that emitted it so that
+/// runtime errors can be reported against the source.
+#[derive(Debug, Clone, Default)]
+pub struct Bytecode {
+    pub ops: Vec<OpCode>,
+    pub spans: Vec<Span>,
+}
+
+impl Bytecode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, op: OpCode, span: Span) {
+        self.ops.push(op);
+        self.spans.push(span);
+    }
+
+    /// Push a jump whose target isn't yet known, returning its index so that it can later be
+    /// fixed up with `patch_jump`.
+    pub fn push_jump(&mut self, jump: fn(usize) -> OpCode, span: Span) -> usize {
+        self.push(jump(usize::MAX), span);
+        self.len() - 1
+    }
+
+    /// Point the jump at `idx` at the next opcode to be pushed.
+    pub fn patch_jump(&mut self, idx: usize) {
+        let target = self.len();
+        match &mut self.ops[idx] {
+            OpCode::Jump(pos)
+            | OpCode::JumpIfFalse(pos)
+            | OpCode::JumpIfFalseOrPop(pos)
+            | OpCode::JumpIfTrueOrPop(pos) => *pos = target,
+            op => unreachable!("Cannot patch non-jump opcode {}", op),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl Index<usize> for Bytecode {
+    type Output = OpCode;
+
+    fn index(&self, idx: usize) -> &OpCode {
+        &self.ops[idx]
+    }
+}
+
+impl IndexMut<usize> for Bytecode {
+    fn index_mut(&mut self, idx: usize) -> &mut OpCode {
+        &mut self.ops[idx]
+    }
+}
+#[derive(Debug)]
+pub enum CompileError {
+    UndefinedVariable { span: Span, name: String },
+    UndefinedFunction { span: Span, name: String },
+    UndeclaredAssignment { span: Span, name: String },
+    BreakOutsideLoop { span: Span },
+    ContinueOutsideLoop { span: Span },
+    DuplicateFunction { span: Span, name: String },
+    BuiltinFunction { span: Span, name: String },
+    AssignToFunction { span: Span, name: String },
+    NameClash { span: Span, name: String },
+}
+
+impl CompileError {
+    /// The span of the expression which caused the error.
+    pub fn span(&self) -> Span {
+        match self {
+            CompileError::UndefinedVariable { span, .. }
+            | CompileError::UndefinedFunction { span, .. }
+            | CompileError::UndeclaredAssignment { span, .. }
+            | CompileError::BreakOutsideLoop { span }
+            | CompileError::ContinueOutsideLoop { span }
+            | CompileError::DuplicateFunction { span, .. }
+            | CompileError::BuiltinFunction { span, .. }
+            | CompileError::AssignToFunction { span, .. }
+            | CompileError::NameClash { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::UndefinedVariable { name, .. } => {
+                write!(f, "Variable '{}' doesn't exist", name)
+            }
+            CompileError::UndefinedFunction { name, .. } => {
+                write!(f, "Function '{}' not found", name)
+            }
+            CompileError::UndeclaredAssignment { name, .. } => {
+                write!(f, "Cannot assign to undeclared variable '{}'", name)
+            }
+            CompileError::BreakOutsideLoop { .. } => write!(f, "'break' outside of a loop"),
+            CompileError::ContinueOutsideLoop { .. } => {
+                write!(f, "'continue' outside of a loop")
+            }
+            CompileError::DuplicateFunction { name, .. } => {
+                write!(f, "Function '{}' is already defined", name)
+            }
+            CompileError::BuiltinFunction { name, .. } => {
+                write!(f, "Cannot redefine builtin function '{}'", name)
+            }
+            CompileError::AssignToFunction { name, .. } => {
+                write!(f, "Cannot assign to function '{}'", name)
+            }
+            CompileError::NameClash { name, .. } => {
+                write!(
+                    f,
+                    "'{}' is declared as both a variable and a function",
+                    name
+                )
+            }
+        }
+    }
+}
+
+/// A loop enclosing the code currently being compiled.
+struct LoopContext {
+    /// Where `continue` jumps to, if it is already known.
+    continue_target: Option<usize>,
+    /// The `continue` jumps to be patched once `continue_target` is known.
+    continues: Vec<usize>,
+    /// The `break` jumps to be patched to the end of the loop.
+    breaks: Vec<usize>,
+}
+
+/// The variables visible to a single function while it is being compiled.
+struct FuncScope {
+    /// The names of the locals currently in scope, indexed by slot. Leaving a block truncates
+    /// this, so slots are reused by later declarations.
+    locals: Vec<String>,
+    upvalues: Vec<(String, Capture)>,
+    loops: Vec<LoopContext>,
+}
+
+impl FuncScope {
+    fn new(locals: Vec<String>) -> Self {
+        Self {
+            locals,
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+        }
+    }
+
+    /// Declare a new local, shadowing any existing variable of the same name, returning its slot.
+    fn declare(&mut self, name: String) -> usize {
+        self.locals.push(name);
+        self.locals.len() - 1
+    }
+}
+
+enum Var {
+    Local(usize),
+    Upvalue(usize),
+    Global,
+}
+
+/// The names declared at the top level of the program.
+struct Globals {
+    /// Every global, whether a variable or a named function.
+    names: HashSet<String>,
+    /// The globals which are named functions, and so can't be assigned to.
+    funcs: HashSet<String>,
+}
+
+/// Resolve `name` in the innermost function of `scopes`. Variables found in an enclosing function
+/// are captured as upvalues by every function between it and the innermost one; names not found
+/// in any function are looked up in `globals`.
+fn resolve(scopes: &mut [FuncScope], globals: &Globals, name: &str) -> Option<Var> {
+    let Some((cur, enclosing)) = scopes.split_last_mut() else {
+        return globals.names.contains(name).then_some(Var::Global);
+    };
+    if let Some(idx) = cur.locals.iter().rposition(|x| x == name) {
+        return Some(Var::Local(idx));
+    }
+    if let Some(idx) = cur.upvalues.iter().position(|(x, _)| x == name) {
+        return Some(Var::Upvalue(idx));
+    }
+    let capture = match resolve(enclosing, globals, name)? {
+        Var::Local(idx) => Capture::Local(idx),
+        Var::Upvalue(idx) => Capture::Upvalue(idx),
+        Var::Global => return Some(Var::Global),
+    };
+    cur.upvalues.push((name.to_string(), capture));
+    Some(Var::Upvalue(cur.upvalues.len() - 1))
+}
+
+/// Compile `ast` into bytecode. Compilation carries on past errors so that every problem in the
+/// program is reported at once.
 pub fn compiler(
     ast: Ast,
     lexer: &dyn NonStreamingLexer<DefaultLexeme<u32>, u32>,
-) -> Result<Vec<OpCode>, String> {
-    if ast.is_empty() {
-        return Ok(Vec::new());
+) -> Result<Bytecode, Vec<CompileError>> {
+    let mut bc = Bytecode::new();
+    let mut scopes = vec![FuncScope::new(Vec::new())];
+    let mut errors = Vec::new();
+    // Variables and named functions declared at the top level are globals, visible inside every
+    // function whether or not their declaration has run yet. A function's name can't be used by
+    // another function, a builtin or a variable, nor assigned to, though variables may shadow
+    // builtins.
+    let mut globals = HashSet::new();
+    let mut func_names = HashSet::new();
+    for node in &ast {
+        let (span, is_func) = match node {
+            config_ast::Expr::Assign { id, .. } => (*id, false),
+            config_ast::Expr::FuncDef {
+                name: Some(name), ..
+            } => (*name, true),
+            _ => continue,
+        };
+        let name = lexer.span_str(span).to_string();
+        if is_func && BUILTINS.contains(&name.as_str()) {
+            errors.push(CompileError::BuiltinFunction { span, name });
+            continue;
+        }
+        if is_func && func_names.contains(&name) {
+            errors.push(CompileError::DuplicateFunction { span, name });
+            continue;
+        }
+        if func_names.contains(&name) || (is_func && globals.contains(&name)) {
+            errors.push(CompileError::NameClash {
+                span,
+                name: name.clone(),
+            });
+        } else if is_func {
+            func_names.insert(name.clone());
+        }
+        globals.insert(name);
+    }
+    let globals = Globals {
+        names: globals,
+        funcs: func_names,
+    };
+    // Named functions at the top level are hoisted, so that they can be called from anywhere in
+    // the program, including by functions defined before them.
+    let (funcs, stmts): (Ast, Ast) = ast
+        .into_iter()
+        .partition(|node| matches!(node, config_ast::Expr::FuncDef { name: Some(_), .. }));
+    let mut defined = HashSet::new();
+    for node in funcs {
+        let config_ast::Expr::FuncDef {
+            span,
+            name: Some(name),
+            args_list,
+            body,
+        } = node
+        else {
+            unreachable!()
+        };
+        let func_name = lexer.span_str(name).to_string();
+        if !globals.funcs.contains(&func_name) || !defined.insert(func_name.clone()) {
+            // The clash has already been reported above.
+            continue;
+        }
+        // Top-level functions are compiled in isolation, as there are no enclosing locals for
+        // them to capture.
+        let args = args_list
+            .iter()
+            .map(|arg| lexer.span_str(*arg).to_string())
+            .collect::<Vec<_>>();
+        let mut func_scopes = vec![FuncScope::new(args.clone())];
+        let mut func_body = Bytecode::new();
+        compiler_expr(
+            &body,
+            lexer,
+            &mut func_scopes,
+            &globals,
+            &mut func_body,
+            &mut errors,
+        );
+        bc.push(
+            OpCode::DefineFunc(func_name, args, Rc::new(func_body)),
+            span,
+        );
+    }
+    for node in stmts {
+        match node {
+            config_ast::Expr::Assign { span, id, expr } => {
+                compiler_expr(&expr, lexer, &mut scopes, &globals, &mut bc, &mut errors);
+                let name = lexer.span_str(id).to_string();
+                bc.push(OpCode::StoreGlobal(name), span);
+            }
+            _ => compiler_stmt(&node, lexer, &mut scopes, &globals, &mut bc, &mut errors),
+        }
+    }
+    if errors.is_empty() {
+        Ok(bc)
+    } else {
+        // Hoisting compiles functions out of order, so put the errors back into source order.
+        errors.sort_by_key(|e| e.span().start());
+        Err(errors)
+    }
+}
+
+/// Compile `node` as a statement, discarding any value it leaves on the stack so that the stack is
+/// empty between statements.
+fn compiler_stmt(
+    node: &config_ast::Expr,
+    lexer: &dyn NonStreamingLexer<DefaultLexeme<u32>, u32>,
+    scopes: &mut Vec<FuncScope>,
+    globals: &Globals,
+    bc: &mut Bytecode,
+    errors: &mut Vec<CompileError>,
+) {
+    compiler_expr(node, lexer, scopes, globals, bc, errors);
+    if is_value(node) {
+        bc.push(OpCode::Pop, node.span());
     }
+}
 
-    let mut bc = Vec::new();
-    let mut locals: Vec<String> = Vec::new();
-    for node in ast {
-        compiler_expr(&node, lexer, &mut locals, &mut bc);
+/// Does `node` leave a value on the stack when compiled?
+fn is_value(node: &config_ast::Expr) -> bool {
+    matches!(
+        node,
+        config_ast::Expr::BinaryOp { .. }
+            | config_ast::Expr::LogicalOp { .. }
+            | config_ast::Expr::UnaryOp { .. }
+            | config_ast::Expr::Int(_)
+            | config_ast::Expr::Float(_)
+            | config_ast::Expr::Bool { .. }
+            | config_ast::Expr::String(_)
+            | config_ast::Expr::Interpolation { .. }
+            | config_ast::Expr::VarLookup(_)
+            | config_ast::Expr::List { .. }
+            | config_ast::Expr::Map { .. }
+            | config_ast::Expr::Index { .. }
+            | config_ast::Expr::Call { .. }
+            | config_ast::Expr::FuncDef { name: None, .. }
+    )
+}
+
+/// Push the integer written as the decimal `digits`, which may be preceded by `-`, using a big
+/// integer only if it does not fit in an `i64`.
+fn push_int(digits: &str, span: Span, bc: &mut Bytecode) {
+    match digits.parse::<i64>() {
+        Ok(x) => bc.push(OpCode::PushInt(x), span),
+        Err(_) => {
+            let x = BigInt::parse(digits).unwrap();
+            bc.push(OpCode::PushBigInt(Rc::new(x)), span);
+        }
     }
-    Ok(bc)
+}
+
+/// Process the escapes in `text`, the contents of a string literal.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        let next_c = chars.next().unwrap_or('\\');
+        unescaped.push(match next_c {
+            't' => '\t',
+            'b' => '\x08',
+            'n' => '\n',
+            'r' => '\r',
+            'f' => '\x0C',
+            '\'' => '\'',
+            '\\' => '\\',
+            '0' => '\0',
+            _ => next_c,
+        });
+    }
+    unescaped
+}
+
+/// Compile a `for` loop from a stack holding its starting count and the value it loops over. It
+/// runs while the count is below `limit`, binding `var` to `item` in each iteration.
+#[allow(clippy::too_many_arguments)]
+fn compiler_for(
+    span: Span,
+    var: Span,
+    body: &config_ast::Expr,
+    limit: impl Fn(usize) -> Vec<OpCode>,
+    item: impl Fn(usize, usize) -> Vec<OpCode>,
+    lexer: &dyn NonStreamingLexer<DefaultLexeme<u32>, u32>,
+    scopes: &mut Vec<FuncScope>,
+    globals: &Globals,
+    bc: &mut Bytecode,
+    errors: &mut Vec<CompileError>,
+) {
+    let depth = scopes.last().unwrap().locals.len();
+    let scope = scopes.last_mut().unwrap();
+    let count_idx = scope.declare("<loop count>".to_string());
+    let over_idx = scope.declare("<loop over>".to_string());
+    bc.push(OpCode::DefineVar(over_idx), span);
+    bc.push(OpCode::DefineVar(count_idx), span);
+
+    let loop_entry = bc.len();
+    bc.push(OpCode::LoadVar(count_idx), span);
+    for op in limit(over_idx) {
+        bc.push(op, span);
+    }
+    bc.push(OpCode::Lt, span);
+    let exit = bc.push_jump(OpCode::JumpIfFalse, span);
+    // Each iteration gets a fresh binding of `var`, so closures capture the value they saw.
+    let var_idx = scopes
+        .last_mut()
+        .unwrap()
+        .declare(lexer.span_str(var).to_string());
+    for op in item(count_idx, over_idx) {
+        bc.push(op, span);
+    }
+    bc.push(OpCode::DefineVar(var_idx), span);
+    scopes.last_mut().unwrap().loops.push(LoopContext {
+        continue_target: None,
+        continues: Vec::new(),
+        breaks: Vec::new(),
+    });
+    compiler_expr(body, lexer, scopes, globals, bc, errors);
+    let ctx = scopes.last_mut().unwrap().loops.pop().unwrap();
+    for cont in ctx.continues {
+        bc.patch_jump(cont);
+    }
+    bc.push(OpCode::LoadVar(count_idx), span);
+    bc.push(OpCode::PushInt(1), span);
+    bc.push(OpCode::Plus, span);
+    bc.push(OpCode::StoreVar(count_idx), span);
+    bc.push(OpCode::Jump(loop_entry), span);
+
+    bc.patch_jump(exit);
+    for brk in ctx.breaks {
+        bc.patch_jump(brk);
+    }
+    scopes.last_mut().unwrap().locals.truncate(depth);
 }
 
 fn compiler_expr(
     node: &config_ast::Expr,
     lexer: &dyn NonStreamingLexer<DefaultLexeme<u32>, u32>,
-    locals: &mut Vec<String>,
-    bc: &mut Vec<OpCode>,
+    scopes: &mut Vec<FuncScope>,
+    globals: &Globals,
+    bc: &mut Bytecode,
+    errors: &mut Vec<CompileError>,
 ) {
+    let span = node.span();
     match node {
-        config_ast::Expr::Int {
+        config_ast::Expr::Int(_) => push_int(lexer.span_str(span), span, bc),
+        config_ast::Expr::Float(_) => bc.push(
+            OpCode::PushFloat(lexer.span_str(span).parse().unwrap()),
+            span,
+        ),
+        config_ast::Expr::Bool { span: _, val } => {
+            bc.push(OpCode::PushBool(*val), span);
+        }
+        config_ast::Expr::String(_) => {
+            // Ignore the beginning and end quotes.
+            let lit = lexer.span_str(span);
+            let text = &lit['"'.len_utf8()..lit.len() - '"'.len_utf8()];
+            bc.push(OpCode::PushStr(unescape(text)), span);
+        }
+        config_ast::Expr::Interpolation {
             span: _,
-            is_negative,
-            val,
+            texts,
+            exprs,
         } => {
-            let mut tmp = lexer.span_str(*val).parse().unwrap();
-            if *is_negative {
-                tmp = -1 * tmp;
-            }
-            bc.push(OpCode::PushInt(tmp));
-        }
-        config_ast::Expr::String(span) => {
-            let s_orig = lexer.span_str(*span);
-            let mut new_s = String::new();
-            // Start by ignoring the beginning quote.
-            let mut i = '\"'.len_utf8();
-            // End by ignoring the beginning quote.
-            while i < s_orig.len() - '\"'.len_utf8() {
-                let mut c = s_orig[i..].chars().next().unwrap();
-                if c == '\\' {
-                    i += c.len_utf8();
-                    let next_c = s_orig[i..].chars().next().unwrap();
-                    c = match next_c {
-                        't' => '\t',
-                        'b' => '\x08',
-                        'n' => '\n',
-                        'r' => '\r',
-                        'f' => '\x0C',
-                        '\'' => '\'',
-                        '\\' => '\\',
-                        '0' => '\0',
-                        _ => next_c,
-                    };
+            // Push each non-empty piece of text and each expression converted to a string, then
+            // join them all together.
+            let mut parts_len = 0;
+            for (i, text) in texts.iter().enumerate() {
+                let text = unescape(lexer.span_str(*text));
+                if !text.is_empty() {
+                    bc.push(OpCode::PushStr(text), span);
+                    parts_len += 1;
+                }
+                if let Some(expr) = exprs.get(i) {
+                    compiler_expr(expr, lexer, scopes, globals, bc, errors);
+                    bc.push(OpCode::ToString, expr.span());
+                    parts_len += 1;
                 }
-                new_s.push(c);
-                i += c.len_utf8();
             }
-
-            bc.push(OpCode::PushStr(new_s));
+            if parts_len > 1 {
+                bc.push(OpCode::Concat(parts_len), span);
+            }
         }
         config_ast::Expr::Assign {
             span: _,
             ref id,
             ref expr,
         } => {
-            compiler_expr(&expr, lexer, locals, bc);
+            compiler_expr(expr, lexer, scopes, globals, bc, errors);
+            let idx_str = lexer.span_str(*id).to_string();
+            let idx = scopes.last_mut().unwrap().declare(idx_str);
+            bc.push(OpCode::DefineVar(idx), span);
+        }
+        config_ast::Expr::Reassign {
+            span: _,
+            ref id,
+            ref expr,
+        } => {
+            compiler_expr(expr, lexer, scopes, globals, bc, errors);
             let idx_str = lexer.span_str(*id).to_string();
-            match locals.iter().position(|x| x == &idx_str) {
-                Some(x) => bc.push(OpCode::StoreVar(x)),
-                None => {
-                    locals.push(idx_str);
-                    bc.push(OpCode::StoreVar(locals.len() - 1));
+            match resolve(scopes, globals, &idx_str) {
+                Some(Var::Local(x)) => bc.push(OpCode::StoreVar(x), span),
+                Some(Var::Upvalue(x)) => bc.push(OpCode::StoreUpvalue(x), span),
+                Some(Var::Global) if globals.funcs.contains(&idx_str) => {
+                    errors.push(CompileError::AssignToFunction {
+                        span: *id,
+                        name: idx_str,
+                    })
                 }
+                Some(Var::Global) => bc.push(OpCode::StoreGlobal(idx_str), span),
+                None => errors.push(CompileError::UndeclaredAssignment {
+                    span,
+                    name: idx_str,
+                }),
             }
         }
         config_ast::Expr::Print { span: _, args } => {
             let label = "print".to_string();
 
-            let args = &*args;
-            compiler_expr(&args, lexer, locals, bc);
+            compiler_expr(args, lexer, scopes, globals, bc, errors);
 
-            bc.push(OpCode::Call(CallTarget::Builtins(label)));
+            bc.push(OpCode::Call(CallTarget::Builtins(label, 1)), span);
         }
         config_ast::Expr::BinaryOp {
             span: _,
@@ -146,37 +677,132 @@ fn compiler_expr(
             lhs,
             rhs,
         } => {
-            compiler_expr(lhs, lexer, locals, bc);
-            compiler_expr(rhs, lexer, locals, bc);
+            compiler_expr(lhs, lexer, scopes, globals, bc, errors);
+            compiler_expr(rhs, lexer, scopes, globals, bc, errors);
             let _op = lexer.span_str(*op);
             match _op {
                 "+" => {
-                    bc.push(OpCode::Plus);
+                    bc.push(OpCode::Plus, span);
                 }
                 "-" => {
-                    bc.push(OpCode::Minus);
+                    bc.push(OpCode::Minus, span);
+                }
+                "*" => {
+                    bc.push(OpCode::Mul, span);
+                }
+                "/" => {
+                    bc.push(OpCode::Div, span);
+                }
+                "%" => {
+                    bc.push(OpCode::Mod, span);
                 }
                 "<" => {
-                    bc.push(OpCode::Lt);
+                    bc.push(OpCode::Lt, span);
                 }
                 "<=" => {
-                    bc.push(OpCode::Lteq);
+                    bc.push(OpCode::Lteq, span);
+                }
+                ">" => {
+                    bc.push(OpCode::Gt, span);
+                }
+                ">=" => {
+                    bc.push(OpCode::Gteq, span);
                 }
                 "==" => {
-                    bc.push(OpCode::Eqeq);
+                    bc.push(OpCode::Eqeq, span);
+                }
+                "!=" => {
+                    bc.push(OpCode::Noteq, span);
+                }
+                &_ => unreachable!(),
+            }
+        }
+        config_ast::Expr::LogicalOp {
+            span: _,
+            op,
+            lhs,
+            rhs,
+        } => {
+            // The rhs is only evaluated if the lhs doesn't decide the result. Both operands go
+            // through the same bool-checking jump, so a type error points at the bad operand.
+            let (jump, rhs_result): (fn(usize) -> OpCode, _) = match lexer.span_str(*op) {
+                "&&" => (OpCode::JumpIfFalseOrPop, true),
+                "||" => (OpCode::JumpIfTrueOrPop, false),
+                &_ => unreachable!(),
+            };
+            compiler_expr(lhs, lexer, scopes, globals, bc, errors);
+            let lhs_exit = bc.push_jump(jump, lhs.span());
+            compiler_expr(rhs, lexer, scopes, globals, bc, errors);
+            let rhs_exit = bc.push_jump(jump, rhs.span());
+            bc.push(OpCode::PushBool(rhs_result), span);
+            bc.patch_jump(lhs_exit);
+            bc.patch_jump(rhs_exit);
+        }
+        config_ast::Expr::UnaryOp { span: _, op, expr } => {
+            match (lexer.span_str(*op), &**expr) {
+                // Negated literals are folded into a single constant. As well as saving an
+                // operation, this is how `i64::MIN`, which has no positive counterpart, is written.
+                ("-", config_ast::Expr::Int(val)) => {
+                    push_int(&format!("-{}", lexer.span_str(*val)), span, bc)
+                }
+                ("-", config_ast::Expr::Float(val)) => {
+                    let x: f64 = lexer.span_str(*val).parse().unwrap();
+                    bc.push(OpCode::PushFloat(-x), span);
+                }
+                (op, _) => {
+                    compiler_expr(expr, lexer, scopes, globals, bc, errors);
+                    match op {
+                        "!" => bc.push(OpCode::Not, span),
+                        "-" => bc.push(OpCode::Neg, span),
+                        &_ => unreachable!(),
+                    }
                 }
-                &_ => todo!(),
             }
         }
         config_ast::Expr::VarLookup(ref id) => {
             let idx_str = lexer.span_str(*id).to_string();
-            let index = match locals.iter().position(|x| x == &idx_str) {
-                Some(x) => x,
-                None => {
-                    panic!("Variable doesn't exists");
-                }
-            };
-            bc.push(OpCode::LoadVar(index));
+            match resolve(scopes, globals, &idx_str) {
+                Some(Var::Local(index)) => bc.push(OpCode::LoadVar(index), span),
+                Some(Var::Upvalue(index)) => bc.push(OpCode::LoadUpvalue(index), span),
+                Some(Var::Global) => bc.push(OpCode::LoadGlobal(idx_str), span),
+                None => errors.push(CompileError::UndefinedVariable {
+                    span,
+                    name: idx_str,
+                }),
+            }
+        }
+        config_ast::Expr::List { span: _, items } => {
+            for item in items {
+                compiler_expr(item, lexer, scopes, globals, bc, errors);
+            }
+            bc.push(OpCode::BuildList(items.len()), span);
+        }
+        config_ast::Expr::Map { span: _, entries } => {
+            for (key, val) in entries {
+                compiler_expr(key, lexer, scopes, globals, bc, errors);
+                compiler_expr(val, lexer, scopes, globals, bc, errors);
+            }
+            bc.push(OpCode::BuildMap(entries.len()), span);
+        }
+        config_ast::Expr::Index {
+            span: _,
+            expr,
+            index,
+        } => {
+            compiler_expr(expr, lexer, scopes, globals, bc, errors);
+            compiler_expr(index, lexer, scopes, globals, bc, errors);
+            bc.push(OpCode::LoadIndex, span);
+        }
+        config_ast::Expr::IndexAssign {
+            span: _,
+            expr,
+            index,
+            val,
+        } => {
+            compiler_expr(expr, lexer, scopes, globals, bc, errors);
+            compiler_expr(index, lexer, scopes, globals, bc, errors);
+            compiler_expr(val, lexer, scopes, globals, bc, errors);
+            bc.push(OpCode::StoreIndex, span);
         }
         config_ast::Expr::WhileLoop {
             span: _,
@@ -184,31 +810,179 @@ fn compiler_expr(
             body,
         } => {
             let loop_entry = bc.len();
-            compiler_expr(condition, lexer, locals, bc);
-            bc.push(OpCode::Patch);
-            let exit = bc.len() - 1;
-            compiler_expr(body, lexer, locals, bc);
-
-            compiler_expr(condition, lexer, locals, bc);
-            bc.push(OpCode::Jump(loop_entry));
+            compiler_expr(condition, lexer, scopes, globals, bc, errors);
+            let exit = bc.push_jump(OpCode::JumpIfFalse, condition.span());
+            scopes.last_mut().unwrap().loops.push(LoopContext {
+                continue_target: Some(loop_entry),
+                continues: Vec::new(),
+                breaks: Vec::new(),
+            });
+            compiler_expr(body, lexer, scopes, globals, bc, errors);
+            bc.push(OpCode::Jump(loop_entry), span);
 
-            bc[exit] = OpCode::JumpIfFalse(bc.len());
+            bc.patch_jump(exit);
+            let ctx = scopes.last_mut().unwrap().loops.pop().unwrap();
+            for brk in ctx.breaks {
+                bc.patch_jump(brk);
+            }
+        }
+        config_ast::Expr::ForLoop {
+            span: _,
+            var,
+            start,
+            end,
+            body,
+        } => {
+            compiler_expr(start, lexer, scopes, globals, bc, errors);
+            bc.push(OpCode::CheckInt, start.span());
+            compiler_expr(end, lexer, scopes, globals, bc, errors);
+            bc.push(OpCode::CheckInt, end.span());
+            let limit = |end_idx| vec![OpCode::LoadVar(end_idx)];
+            let item = |count_idx, _| vec![OpCode::LoadVar(count_idx)];
+            compiler_for(
+                span, *var, body, limit, item, lexer, scopes, globals, bc, errors,
+            );
+        }
+        config_ast::Expr::ForEach {
+            span: _,
+            var,
+            iter,
+            body,
+        } => {
+            // Maps and strings are iterated over as a list of their keys or characters. The length
+            // is checked on every iteration, so items pushed by the body are visited too.
+            bc.push(OpCode::PushInt(0), span);
+            compiler_expr(iter, lexer, scopes, globals, bc, errors);
+            bc.push(OpCode::ToList, iter.span());
+            let limit = |list_idx| {
+                vec![
+                    OpCode::LoadVar(list_idx),
+                    OpCode::Call(CallTarget::Builtins("len".to_string(), 1)),
+                ]
+            };
+            let item = |index_idx, list_idx| {
+                vec![
+                    OpCode::LoadVar(list_idx),
+                    OpCode::LoadVar(index_idx),
+                    OpCode::LoadIndex,
+                ]
+            };
+            compiler_for(
+                span, *var, body, limit, item, lexer, scopes, globals, bc, errors,
+            );
         }
         config_ast::Expr::IfStatement {
             span: _,
             condition,
             body,
+            else_body,
         } => {
-            compiler_expr(condition, lexer, locals, bc);
-            bc.push(OpCode::Patch);
-            let exit = bc.len() - 1;
-            compiler_expr(body, lexer, locals, bc);
-            bc[exit] = OpCode::JumpIfFalse(bc.len());
+            compiler_expr(condition, lexer, scopes, globals, bc, errors);
+            let exit = bc.push_jump(OpCode::JumpIfFalse, condition.span());
+            compiler_expr(body, lexer, scopes, globals, bc, errors);
+            match else_body {
+                Some(else_body) => {
+                    // Jump over the else branch once the if branch has run.
+                    let end = bc.push_jump(OpCode::Jump, span);
+                    bc.patch_jump(exit);
+                    compiler_expr(else_body, lexer, scopes, globals, bc, errors);
+                    bc.patch_jump(end);
+                }
+                None => bc.patch_jump(exit),
+            }
         }
+        config_ast::Expr::Break(_) => match scopes.last_mut().unwrap().loops.last_mut() {
+            Some(ctx) => ctx.breaks.push(bc.push_jump(OpCode::Jump, span)),
+            None => errors.push(CompileError::BreakOutsideLoop { span }),
+        },
+        config_ast::Expr::Continue(_) => match scopes.last_mut().unwrap().loops.last_mut() {
+            Some(LoopContext {
+                continue_target: Some(target),
+                ..
+            }) => bc.push(OpCode::Jump(*target), span),
+            Some(ctx) => ctx.continues.push(bc.push_jump(OpCode::Jump, span)),
+            None => errors.push(CompileError::ContinueOutsideLoop { span }),
+        },
         config_ast::Expr::Prog { span: _, stmts } => {
-            for stmt in stmts {
-                compiler_expr(stmt, lexer, locals, bc);
+            // Variables declared inside a block go out of scope at its end.
+            let depth = scopes.last().unwrap().locals.len();
+            // Named functions are created on entry to the block, and can use the variables
+            // declared before them in it, whose slots are reserved on entry too.
+            let last_func = stmts
+                .iter()
+                .rposition(|stmt| matches!(stmt, config_ast::Expr::FuncDef { name: Some(_), .. }));
+            let let_names = stmts
+                .iter()
+                .filter_map(|stmt| match stmt {
+                    config_ast::Expr::Assign { id, .. } => Some(lexer.span_str(*id)),
+                    _ => None,
+                })
+                .collect::<HashSet<_>>();
+            let mut reserved = Vec::new();
+            let mut funcs = Vec::new();
+            let mut func_names = HashSet::new();
+            for (i, stmt) in stmts.iter().enumerate() {
+                match stmt {
+                    config_ast::Expr::Assign { id, .. } if last_func.is_some_and(|j| i < j) => {
+                        let name = lexer.span_str(*id).to_string();
+                        let slot = scopes.last_mut().unwrap().declare("<reserved>".to_string());
+                        bc.push(OpCode::PushUndefined(name.clone()), stmt.span());
+                        bc.push(OpCode::DefineVar(slot), stmt.span());
+                        reserved.push((i, slot, name));
+                    }
+                    config_ast::Expr::FuncDef {
+                        name: Some(name), ..
+                    } => {
+                        let func_name = lexer.span_str(*name).to_string();
+                        if !func_names.insert(func_name.clone()) {
+                            errors.push(CompileError::DuplicateFunction {
+                                span: *name,
+                                name: func_name,
+                            });
+                            continue;
+                        }
+                        if let_names.contains(func_name.as_str()) {
+                            errors.push(CompileError::NameClash {
+                                span: *name,
+                                name: func_name.clone(),
+                            });
+                        }
+                        let slot = scopes.last_mut().unwrap().declare(func_name);
+                        bc.push(OpCode::PushNone, stmt.span());
+                        bc.push(OpCode::DefineVar(slot), stmt.span());
+                        funcs.push((i, slot));
+                    }
+                    _ => (),
+                }
+            }
+            for (i, slot) in funcs {
+                let locals = &mut scopes.last_mut().unwrap().locals;
+                for (_, var_slot, name) in reserved.iter().filter(|(j, ..)| *j < i) {
+                    locals[*var_slot] = name.clone();
+                }
+                compiler_expr(&stmts[i], lexer, scopes, globals, bc, errors);
+                bc.push(OpCode::StoreVar(slot), stmts[i].span());
+                let locals = &mut scopes.last_mut().unwrap().locals;
+                for (_, var_slot, _) in &reserved {
+                    locals[*var_slot] = "<reserved>".to_string();
+                }
+            }
+            let mut reserved = reserved.into_iter().peekable();
+            for (i, stmt) in stmts.iter().enumerate() {
+                match stmt {
+                    config_ast::Expr::FuncDef { name: Some(_), .. } => (),
+                    config_ast::Expr::Assign { expr, .. }
+                        if reserved.peek().is_some_and(|(j, ..)| *j == i) =>
+                    {
+                        let (_, slot, name) = reserved.next().unwrap();
+                        compiler_expr(expr, lexer, scopes, globals, bc, errors);
+                        scopes.last_mut().unwrap().locals[slot] = name;
+                        bc.push(OpCode::StoreVar(slot), stmt.span());
+                    }
+                    _ => compiler_stmt(stmt, lexer, scopes, globals, bc, errors),
+                }
             }
+            scopes.last_mut().unwrap().locals.truncate(depth);
         }
         config_ast::Expr::FuncDef {
             span: _,
@@ -216,49 +990,68 @@ fn compiler_expr(
             args_list,
             body,
         } => {
-            let mut new_locals = Vec::new();
-            let mut args = Vec::new();
-            let mut func_body = Vec::new();
-            let func_name = name.map_or("".to_string(), |n| lexer.span_str(n).to_string());
-            for arg in args_list {
-                let val = lexer.span_str(*arg).to_string();
-                new_locals.push(val);
-            }
-            args.extend(new_locals.clone());
-            bc.push(OpCode::Patch);
-
-            let offset = bc.len() - 1;
-            compiler_expr(body, lexer, &mut new_locals, &mut func_body);
-
-            if func_name.is_empty() {
-                bc[offset] = OpCode::InlineFunc(args, func_body, new_locals);
-            } else {
-                bc[offset] = OpCode::DefineFunc(func_name.clone(), args, func_body, new_locals);
-            }
+            // Functions other than those at the top level are closures over the variables of the
+            // functions which enclose them.
+            let args = args_list
+                .iter()
+                .map(|arg| lexer.span_str(*arg).to_string())
+                .collect::<Vec<_>>();
+            let func_name = name.map(|n| lexer.span_str(n).to_string());
+            let mut func_body = Bytecode::new();
+            scopes.push(FuncScope::new(args.clone()));
+            compiler_expr(body, lexer, scopes, globals, &mut func_body, errors);
+            let scope = scopes.pop().unwrap();
+            let captures = scope.upvalues.into_iter().map(|(_, c)| c).collect();
+            bc.push(
+                OpCode::InlineFunc(func_name, args, Rc::new(func_body), captures),
+                span,
+            );
         }
 
         config_ast::Expr::Call {
             span: _,
-            name,
+            callee,
             params,
         } => {
+            let params_len = params.len();
+            let config_ast::Expr::VarLookup(name) = **callee else {
+                // Anything other than a name is evaluated to find the function to call.
+                compiler_expr(callee, lexer, scopes, globals, bc, errors);
+                for param in params {
+                    compiler_expr(param, lexer, scopes, globals, bc, errors);
+                }
+                bc.push(OpCode::Call(CallTarget::Value(params_len)), span);
+                return;
+            };
             for param in params {
-                compiler_expr(param, lexer, locals, bc);
+                compiler_expr(param, lexer, scopes, globals, bc, errors);
             }
-            let params_len = params.len();
-            let func_name = lexer.span_str(*name).to_string();
-            let index = locals.iter().position(|x| x == &func_name);
-            let func_name = lexer.span_str(*name).to_string();
-            if let Some(index) = locals.iter().position(|x| x == &func_name) {
-                bc.push(OpCode::Call(CallTarget::Var(index, params_len)));
-            } else {
-                bc.push(OpCode::Call(CallTarget::Func(func_name, params_len)));
+            let func_name = lexer.span_str(name).to_string();
+            match resolve(scopes, globals, &func_name) {
+                Some(Var::Local(index)) => {
+                    bc.push(OpCode::Call(CallTarget::Var(index, params_len)), span)
+                }
+                Some(Var::Upvalue(index)) => {
+                    bc.push(OpCode::Call(CallTarget::Upvalue(index, params_len)), span)
+                }
+                Some(Var::Global) => bc.push(
+                    OpCode::Call(CallTarget::Global(func_name, params_len)),
+                    span,
+                ),
+                None if BUILTINS.contains(&func_name.as_str()) => bc.push(
+                    OpCode::Call(CallTarget::Builtins(func_name, params_len)),
+                    span,
+                ),
+                None => errors.push(CompileError::UndefinedFunction {
+                    span: name,
+                    name: func_name,
+                }),
             }
         }
 
         config_ast::Expr::Return { span: _, expr } => {
-            compiler_expr(&*expr, lexer, locals, bc);
-            bc.push(OpCode::Return);
+            compiler_expr(expr, lexer, scopes, globals, bc, errors);
+            bc.push(OpCode::Return, span);
         }
     }
 }
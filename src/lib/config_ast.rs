@@ -6,11 +6,18 @@ pub enum Expr {
         span: Span,
         stmts: Vec<Expr>,
     },
+    /// `let id = expr`, declaring a new variable in the enclosing block.
     Assign {
         span: Span,
         id: Span,
         expr: Box<Expr>,
     },
+    /// `id = expr`, updating an existing variable.
+    Reassign {
+        span: Span,
+        id: Span,
+        expr: Box<Expr>,
+    },
     Print {
         span: Span,
         args: Box<Expr>,
@@ -21,22 +28,82 @@ pub enum Expr {
         lhs: Box<Expr>,
         rhs: Box<Expr>,
     },
-    Int {
+    LogicalOp {
+        span: Span,
+        op: Span,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    UnaryOp {
+        span: Span,
+        op: Span,
+        expr: Box<Expr>,
+    },
+    Int(Span),
+    Float(Span),
+    Bool {
         span: Span,
-        is_negative: bool,
-        val: Span,
+        val: bool,
     },
     String(Span),
+    /// `"text ${expr} text"`. `texts` holds the text (with escapes still unprocessed) before, between
+    /// and after `exprs`, so it is always one longer than `exprs`.
+    Interpolation {
+        span: Span,
+        texts: Vec<Span>,
+        exprs: Vec<Expr>,
+    },
     VarLookup(Span),
+    /// `[items]`
+    List {
+        span: Span,
+        items: Vec<Expr>,
+    },
+    /// `{key: val, ...}`
+    Map {
+        span: Span,
+        entries: Vec<(Expr, Expr)>,
+    },
+    /// `expr[index]`
+    Index {
+        span: Span,
+        expr: Box<Expr>,
+        index: Box<Expr>,
+    },
+    /// `expr[index] = val`
+    IndexAssign {
+        span: Span,
+        expr: Box<Expr>,
+        index: Box<Expr>,
+        val: Box<Expr>,
+    },
     WhileLoop {
         span: Span,
         condition: Box<Expr>,
         body: Box<Expr>,
     },
+    ForLoop {
+        span: Span,
+        var: Span,
+        start: Box<Expr>,
+        end: Box<Expr>,
+        body: Box<Expr>,
+    },
+    /// `for (var in iter) body`, looping over the items of a list, the keys of a map or the
+    /// characters of a string.
+    ForEach {
+        span: Span,
+        var: Span,
+        iter: Box<Expr>,
+        body: Box<Expr>,
+    },
+    Break(Span),
+    Continue(Span),
     IfStatement {
         span: Span,
         condition: Box<Expr>,
         body: Box<Expr>,
+        else_body: Option<Box<Expr>>,
     },
     FuncDef {
         span: Span,
@@ -44,9 +111,10 @@ pub enum Expr {
         args_list: Vec<Span>,
         body: Box<Expr>,
     },
+    /// `callee(params)`
     Call {
         span: Span,
-        name: Span,
+        callee: Box<Expr>,
         params: Vec<Expr>,
     },
     Return {
@@ -59,12 +127,26 @@ impl Expr {
     pub fn span(&self) -> Span {
         match self {
             Expr::Assign { span, .. } => *span,
+            Expr::Reassign { span, .. } => *span,
             Expr::String(span) => *span,
+            Expr::Interpolation { span, .. } => *span,
             Expr::BinaryOp { span, .. } => *span,
-            Expr::Int { span, .. } => *span,
+            Expr::LogicalOp { span, .. } => *span,
+            Expr::UnaryOp { span, .. } => *span,
+            Expr::Int(span) => *span,
+            Expr::Float(span) => *span,
+            Expr::Bool { span, .. } => *span,
             Expr::VarLookup(span) => *span,
+            Expr::List { span, .. } => *span,
+            Expr::Map { span, .. } => *span,
+            Expr::Index { span, .. } => *span,
+            Expr::IndexAssign { span, .. } => *span,
             Expr::Print { span, .. } => *span,
             Expr::WhileLoop { span, .. } => *span,
+            Expr::ForLoop { span, .. } => *span,
+            Expr::ForEach { span, .. } => *span,
+            Expr::Break(span) => *span,
+            Expr::Continue(span) => *span,
             Expr::IfStatement { span, .. } => *span,
             Expr::Prog { span, .. } => *span,
             Expr::FuncDef { span, .. } => *span,
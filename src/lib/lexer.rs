@@ -0,0 +1,151 @@
+use crate::ukiyo_l::{T_STRING, T_STRING_END, T_STRING_MID, T_STRING_START};
+use lrlex::{DefaultLexeme, LRNonStreamingLexer, LRNonStreamingLexerDef};
+use lrpar::{LexError, Lexeme, Lexer, Span};
+
+type LexResult = Result<DefaultLexeme<u32>, LexError>;
+
+/// Lex `src` with `lexerdef`. lrlex has no lexer modes, so it cannot find the end of a `${...}`
+/// interpolation, which may itself contain braces and string literals. Instead, string literals
+/// are scanned here: one without interpolations is a single `STRING` token, while one with them
+/// is split into `STRING_START`, `STRING_MID` and `STRING_END` tokens around the interpolated
+/// expressions. These tokens have no rules in `ukiyo.l`, so their IDs come from the grammar. The
+/// code between string literals is lexed by `lexerdef` as normal.
+pub fn lex<'lexer, 'input>(
+    lexerdef: &'lexer LRNonStreamingLexerDef<DefaultLexeme<u32>, u32>,
+    src: &'input str,
+) -> LRNonStreamingLexer<'lexer, 'input, DefaultLexeme<u32>, u32> {
+    let mut scanner = Scanner {
+        lexerdef,
+        src,
+        lexemes: Vec::new(),
+    };
+    // As with lrlex, lexing stops at the first error, which is the last lexeme.
+    let _ = scanner.code(0, false);
+    let newlines = src.match_indices('\n').map(|(i, _)| i + 1).collect();
+    LRNonStreamingLexer::new(src, scanner.lexemes, newlines)
+}
+
+struct Scanner<'a> {
+    lexerdef: &'a LRNonStreamingLexerDef<DefaultLexeme<u32>, u32>,
+    src: &'a str,
+    lexemes: Vec<LexResult>,
+}
+
+impl Scanner<'_> {
+    /// Lex code starting at `pos` up to the end of the input or, if `in_interpolation`, up to the
+    /// `}` which closes the interpolation. Returns the position reached, or `None` if lexing
+    /// failed.
+    fn code(&mut self, mut pos: usize, in_interpolation: bool) -> Option<usize> {
+        let bytes = self.src.as_bytes();
+        let mut seg_start = pos;
+        let mut depth = 0;
+        while pos < bytes.len() {
+            match bytes[pos] {
+                b'"' => {
+                    self.segment(seg_start, pos)?;
+                    pos = self.string(pos)?;
+                    seg_start = pos;
+                    continue;
+                }
+                // Skip comments, so that quotes and braces inside them are ignored.
+                b'/' if bytes.get(pos + 1) == Some(&b'/') => {
+                    pos = self.src[pos..].find('\n').map_or(bytes.len(), |i| pos + i);
+                    continue;
+                }
+                b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                    // Block comments may span lines, so they are skipped here rather than being
+                    // left to `lexerdef`, which would only see the part of one inside a segment.
+                    self.segment(seg_start, pos)?;
+                    match self.src[pos + 2..].find("*/") {
+                        Some(i) => pos += 2 + i + 2,
+                        None => return self.error(pos),
+                    }
+                    seg_start = pos;
+                    continue;
+                }
+                b'{' if in_interpolation => depth += 1,
+                b'}' if in_interpolation => {
+                    if depth == 0 {
+                        self.segment(seg_start, pos)?;
+                        return Some(pos);
+                    }
+                    depth -= 1;
+                }
+                _ => (),
+            }
+            pos += 1;
+        }
+        self.segment(seg_start, pos)?;
+        Some(pos)
+    }
+
+    /// Lex the string literal starting with the `"` at `start`, returning the position after it.
+    fn string(&mut self, start: usize) -> Option<usize> {
+        let bytes = self.src.as_bytes();
+        let mut piece_start = start;
+        let mut interpolated = false;
+        let mut pos = start + 1;
+        loop {
+            match bytes.get(pos) {
+                None | Some(b'\n') => return self.error(piece_start),
+                Some(b'\\') => {
+                    pos += 1;
+                    if bytes.get(pos).is_some_and(|&b| b != b'\n') {
+                        pos += 1;
+                    }
+                }
+                Some(b'"') => {
+                    pos += 1;
+                    let tok_id = if interpolated { T_STRING_END } else { T_STRING };
+                    self.push(tok_id, piece_start, pos);
+                    return Some(pos);
+                }
+                Some(b'$') if bytes.get(pos + 1) == Some(&b'{') => {
+                    pos += 2;
+                    let tok_id = if interpolated {
+                        T_STRING_MID
+                    } else {
+                        T_STRING_START
+                    };
+                    self.push(tok_id, piece_start, pos);
+                    interpolated = true;
+                    let open = pos - 2;
+                    pos = self.code(pos, true)?;
+                    if pos == bytes.len() {
+                        return self.error(open);
+                    }
+                    // The `}` closing the interpolation starts the next piece of text.
+                    piece_start = pos;
+                    pos += 1;
+                }
+                Some(_) => pos += 1,
+            }
+        }
+    }
+
+    /// Lex the code between `start` and `end` with lrlex.
+    fn segment(&mut self, start: usize, end: usize) -> Option<()> {
+        let lexer = self.lexerdef.lexer(&self.src[start..end]);
+        for lexeme in lexer.iter() {
+            match lexeme {
+                Ok(l) => self.lexemes.push(Ok(DefaultLexeme::new(
+                    l.tok_id(),
+                    start + l.span().start(),
+                    l.span().len(),
+                ))),
+                Err(e) => return self.error(start + e.span().start()),
+            }
+        }
+        Some(())
+    }
+
+    fn push(&mut self, tok_id: u32, start: usize, end: usize) {
+        self.lexemes
+            .push(Ok(DefaultLexeme::new(tok_id, start, end - start)));
+    }
+
+    fn error<T>(&mut self, pos: usize) -> Option<T> {
+        self.lexemes.push(Err(LexError::new(Span::new(pos, pos))));
+        None
+    }
+}
@@ -1,24 +1,78 @@
-use lrlex::lrlex_mod;
-use lrpar::lrpar_mod;
+use lrlex::{lrlex_mod, DefaultLexeme};
+use lrpar::{lrpar_mod, NonStreamingLexer, Span};
+pub mod bigint;
 pub mod compiler;
 pub mod config_ast;
+pub mod lexer;
 pub mod vm;
+use compiler::compiler;
 use vm::run;
 lrlex_mod!("lib/ukiyo.l");
 lrpar_mod!("lib/ukiyo.y");
 
-pub fn compile(contents: String) {
+/// Render `msg` as an error pointing at `span`, in the form `file:line:col` followed by the
+/// offending line of source with the span underlined.
+fn pp_error(
+    file_name: &str,
+    lexer: &dyn NonStreamingLexer<DefaultLexeme<u32>, u32>,
+    span: Span,
+    msg: &str,
+) -> String {
+    let ((line, col), (end_line, end_col)) = lexer.line_col(span);
+    let src_line = lexer.span_lines_str(span).lines().next().unwrap_or("");
+    let underline_len = if end_line == line {
+        end_col.saturating_sub(col).max(1)
+    } else {
+        (src_line.chars().count() + 1).saturating_sub(col).max(1)
+    };
+    let gutter = " ".repeat(line.to_string().len());
+    // Keep the tabs before the span, so that the underline lines up however they're displayed.
+    let padding = src_line
+        .chars()
+        .take(col - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    format!(
+        "{msg}\n{gutter}--> {file_name}:{line}:{col}\n{gutter} |\n{line} | {src_line}\n{gutter} | {padding}{}",
+        "^".repeat(underline_len)
+    )
+}
+
+/// Compile and run `contents`, reporting any errors on stderr. Returns `true` if the program
+/// compiled and ran without errors.
+pub fn compile(file_name: &str, contents: String) -> bool {
     // Use the contents string as needed within the function
     let lexerdef = ukiyo_l::lexerdef();
-    let lexer = lexerdef.lexer(&contents);
+    let lexer = lexer::lex(&lexerdef, &contents);
     let (res, errs) = ukiyo_y::parse(&lexer);
-    for e in errs {
-        println!("{}", e.pp(&lexer, &ukiyo_y::token_epp));
+    if !errs.is_empty() {
+        for e in errs {
+            eprintln!("{}", e.pp(&lexer, &ukiyo_y::token_epp));
+        }
+        return false;
     }
     match res {
         Some(Ok(r)) => {
-            run(r, &lexer);
+            let prog = match compiler(r, &lexer) {
+                Ok(prog) => prog,
+                Err(errs) => {
+                    for e in errs {
+                        let msg = format!("Compile error: {}", e);
+                        eprintln!("{}", pp_error(file_name, &lexer, e.span(), &msg));
+                    }
+                    return false;
+                }
+            };
+            if let Err(e) = run(prog) {
+                let msg = format!("Runtime error: {}", e);
+                eprintln!("{}", pp_error(file_name, &lexer, e.span(), &msg));
+                return false;
+            }
+            true
+        }
+        _ => {
+            eprintln!("Unable to evaluate expression.");
+            false
         }
-        _ => eprintln!("Unable to evaluate expression."),
     }
 }
@@ -10,17 +10,15 @@ statement -> Result<Expr, ()>:
           | assigment  "SEMICOLON" { $1 }
           | print_statement "SEMICOLON" { $1 }
           | while_loop { $1 }
+          | for_loop { $1 }
           | if_statement { $1 }
           | func_def { $1 }
           | anon_func { $1 }
           | return_statement { $1 }
+          | "BREAK" "SEMICOLON" { Ok(Expr::Break($span)) }
+          | "CONTINUE" "SEMICOLON" { Ok(Expr::Continue($span)) }
           ;
 
-func_call -> Result<Expr, ()>:
-            "IDENTIFIER" "LBRACK" param_list "RBRACK" {
-              Ok(Expr::Call { span: $span, name: map_err($1)?, params: $3?})
-            };
-
 param_list -> Result<Vec<Expr>, ()>:
           { Ok(vec![]) }
         | binary_expression { Ok(vec![$1?]) }
@@ -59,9 +57,15 @@ args -> Result<Vec<Span>, ()>:
        ;
         
 if_statement -> Result<Expr, ()>:
-                "IF" "LBRACK" binary_expression "RBRACK"  body {
-                  Ok(Expr::IfStatement { span: $span, condition: Box::new($3?), body: Box::new($5?)})
+                "IF" "LBRACK" binary_expression "RBRACK"  body else_branch {
+                  Ok(Expr::IfStatement { span: $span, condition: Box::new($3?), body: Box::new($5?), else_body: $6?})
                 };
+
+else_branch -> Result<Option<Box<Expr>>, ()>:
+                { Ok(None) }
+              | "ELSE" body { Ok(Some(Box::new($2?))) }
+              | "ELSE" if_statement { Ok(Some(Box::new($2?))) }
+              ;
 print_statement -> Result<Expr, ()>: 
                    "PRINT" "LBRACK" binary_expression "RBRACK" {  
                    Ok(Expr::Print { span: $span, args: Box::new($3?)})
@@ -72,6 +76,14 @@ while_loop -> Result<Expr, ()>:
               Ok(Expr::WhileLoop { span: $span, condition: Box::new($3?), body: Box::new($5?)})
               };
 
+for_loop -> Result<Expr, ()>:
+              "FOR" "LBRACK" "IDENTIFIER" "IN" binary_expression "DOTDOT" binary_expression "RBRACK" body {
+              Ok(Expr::ForLoop { span: $span, var: map_err($3)?, start: Box::new($5?), end: Box::new($7?), body: Box::new($9?)})
+              }
+            | "FOR" "LBRACK" "IDENTIFIER" "IN" binary_expression "RBRACK" body {
+              Ok(Expr::ForEach { span: $span, var: map_err($3)?, iter: Box::new($5?), body: Box::new($7?)})
+              };
+
 body -> Result<Expr, ()>:
         "LBRACE" prog "RBRACE" { Ok(Expr::Prog { span: $span, stmts: $2?}) }
       | return_statement { $1 }
@@ -90,37 +102,123 @@ assigment -> Result<Expr, ()>:
         | "LET" "IDENTIFIER" "EQ" anon_func {
             Ok(Expr::Assign { span: $span, id: map_err($2)?, expr: Box::new($4?)})
           }
+        | "IDENTIFIER" "EQ" binary_expression {
+            Ok(Expr::Reassign { span: $span, id: map_err($1)?, expr: Box::new($3?)})
+          }
+        | "IDENTIFIER" "EQ" anon_func {
+            Ok(Expr::Reassign { span: $span, id: map_err($1)?, expr: Box::new($3?)})
+          }
+        | binary_term "LSQUARE" binary_expression "RSQUARE" "EQ" binary_expression {
+            Ok(Expr::IndexAssign { span: $span, expr: Box::new($1?), index: Box::new($3?), val: Box::new($6?)})
+          }
         ;
 
 unit -> Result<Expr, ()>:
         literal { $1 }
       | "IDENTIFIER" { Ok(Expr::VarLookup(map_err($1)?)) } 
       | "LBRACK" binary_expression "RBRACK" { $2 }
+      | "LBRACK" anon_func "RBRACK" { $2 }
+      | "LSQUARE" param_list "RSQUARE" { Ok(Expr::List { span: $span, items: $2? }) }
+      | "LBRACE" map_entries "RBRACE" { Ok(Expr::Map { span: $span, entries: $2? }) }
+      | interpolation { $1 }
       ;
+
+interpolation -> Result<Expr, ()>:
+          "STRING_START" interpolation_parts "STRING_END" {
+            let (mut texts, exprs) = $2?;
+            texts.insert(0, text_span($1, 1, 2)?);
+            texts.push(text_span($3, 1, 1)?);
+            Ok(Expr::Interpolation { span: $span, texts, exprs })
+          }
+        ;
+
+interpolation_parts -> Result<(Vec<Span>, Vec<Expr>), ()>:
+          binary_expression { Ok((vec![], vec![$1?])) }
+        | interpolation_parts "STRING_MID" binary_expression {
+            let (mut texts, mut exprs) = $1?;
+            texts.push(text_span($2, 1, 2)?);
+            exprs.push($3?);
+            Ok((texts, exprs))
+          }
+        ;
+
+map_entries -> Result<Vec<(Expr, Expr)>, ()>:
+          { Ok(vec![]) }
+        | map_entry { Ok(vec![$1?]) }
+        | map_entries "COMMA" map_entry { flattenr($1, $3) }
+        ;
+
+map_entry -> Result<(Expr, Expr), ()>:
+          binary_expression "COLON" binary_expression { Ok(($1?, $3?)) }
+        ;
           
 literal -> Result<Expr, ()>: 
-          "INT" { Ok(Expr::Int{ span: $span, is_negative: false, val: map_err($1)? }) }
-        | "MINUS" "INT" { Ok(Expr::Int{ span: $span, is_negative: true, val: map_err($2)? }) }
+          "INT" { Ok(Expr::Int(map_err($1)?)) }
+        | "FLOAT" { Ok(Expr::Float(map_err($1)?)) }
         | "STRING" { Ok(Expr::String(map_err($1)?)) }
+        | "TRUE" { Ok(Expr::Bool{ span: $span, val: true }) }
+        | "FALSE" { Ok(Expr::Bool{ span: $span, val: false }) }
         ;
 
 binary_expression -> Result<Expr, ()>: 
-                    binary_expression bin_op binary_term { Ok(Expr::BinaryOp { span: $span, op: $2?, lhs: Box::new($1?), rhs: Box::new($3?)} ) }
+                    binary_expression "OR" and_expression { Ok(Expr::LogicalOp { span: $span, op: map_err($2)?, lhs: Box::new($1?), rhs: Box::new($3?)} ) }
+                  | and_expression { $1 }
+                  ;
+
+and_expression -> Result<Expr, ()>:
+                    and_expression "AND" comparison_expression { Ok(Expr::LogicalOp { span: $span, op: map_err($2)?, lhs: Box::new($1?), rhs: Box::new($3?)} ) }
+                  | comparison_expression { $1 }
+                  ;
+
+comparison_expression -> Result<Expr, ()>:
+                    comparison_expression cmp_op additive_expression { Ok(Expr::BinaryOp { span: $span, op: $2?, lhs: Box::new($1?), rhs: Box::new($3?)} ) }
+                  | additive_expression { $1 }
+                  ;
+
+additive_expression -> Result<Expr, ()>:
+                    additive_expression add_op multiplicative_expression { Ok(Expr::BinaryOp { span: $span, op: $2?, lhs: Box::new($1?), rhs: Box::new($3?)} ) }
+                  | multiplicative_expression { $1 }
+                  ;
+
+multiplicative_expression -> Result<Expr, ()>:
+                    multiplicative_expression mul_op unary_expression { Ok(Expr::BinaryOp { span: $span, op: $2?, lhs: Box::new($1?), rhs: Box::new($3?)} ) }
+                  | unary_expression { $1 }
+                  ;
+
+unary_expression -> Result<Expr, ()>:
+                    "NOT" unary_expression { Ok(Expr::UnaryOp { span: $span, op: map_err($1)?, expr: Box::new($2?)} ) }
+                  | "MINUS" unary_expression { Ok(Expr::UnaryOp { span: $span, op: map_err($1)?, expr: Box::new($2?)} ) }
                   | binary_term { $1 }
                   ;
 
 binary_term -> Result<Expr, ()>:
                unit { $1 }
-              | func_call { $1 }
+              | binary_term "LBRACK" param_list "RBRACK" {
+                  Ok(Expr::Call { span: $span, callee: Box::new($1?), params: $3?})
+                }
+              | binary_term "LSQUARE" binary_expression "RSQUARE" {
+                  Ok(Expr::Index { span: $span, expr: Box::new($1?), index: Box::new($3?)})
+                }
               ;
-bin_op -> Result<Span, ()>: 
-           "PLUS"  { Ok(map_err($1)?) }
-        | "MINUS" { Ok(map_err($1)?) }
-        | "LTEQ"  { Ok(map_err($1)?) }
-        | "GTEQ"  { Ok(map_err($1)?) }
-        | "LT"    { Ok(map_err($1)?) }
-        | "GT"    { Ok(map_err($1)?) }
-        | "EQEQ"  { Ok(map_err($1)?) }
+
+cmp_op -> Result<Span, ()>: 
+          "LTEQ"  { map_err($1) }
+        | "GTEQ"  { map_err($1) }
+        | "LT"    { map_err($1) }
+        | "GT"    { map_err($1) }
+        | "EQEQ"  { map_err($1) }
+        | "NOTEQ" { map_err($1) }
+        ;
+
+add_op -> Result<Span, ()>: 
+          "PLUS"  { map_err($1) }
+        | "MINUS" { map_err($1) }
+        ;
+
+mul_op -> Result<Span, ()>: 
+          "STAR"    { map_err($1) }
+        | "SLASH"   { map_err($1) }
+        | "PERCENT" { map_err($1) }
         ;
 %%
 use crate::config_ast::{ Expr };
@@ -136,6 +234,15 @@ fn map_err(r: Result<DefaultLexeme<StorageT>, DefaultLexeme<StorageT>>)
     r.map(|x| x.span()).map_err(|_| ())
 }
 
+/// The span of the text in a piece of an interpolated string, without the `open` bytes of
+/// delimiter (`"` or `}`) at its start and the `close` bytes (`${` or `"`) at its end.
+fn text_span(r: Result<DefaultLexeme<StorageT>, DefaultLexeme<StorageT>>, open: usize, close: usize)
+    -> Result<Span, ()>
+{
+    let span = map_err(r)?;
+    Ok(Span::new(span.start() + open, span.end() - close))
+}
+
 /// Flatten `rhs` into `lhs`.
 fn flattenr<T>(lhs: Result<Vec<T>, ()>, rhs: Result<T, ()>) -> Result<Vec<T>, ()> {
     let mut flt = lhs?;
@@ -1,269 +1,1370 @@
-use crate::compiler::{compiler, Ast, CallTarget, OpCode};
-use core::panic;
-use lrlex::DefaultLexeme;
-use lrpar::NonStreamingLexer;
-use std::fmt;
+use crate::{
+    bigint::BigInt,
+    compiler::{Bytecode, CallTarget, Capture, OpCode},
+};
+use lrpar::Span;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    rc::Rc,
+};
 
 #[derive(Debug, Clone)]
 pub enum Types {
-    Int(i32),
+    Int(i64),
+    /// An integer too large to fit in an `Int`. Integers which fit in an `Int` are never stored as
+    /// a `BigInt`.
+    BigInt(Rc<BigInt>),
+    Float(f64),
     String(String),
     Bool(bool),
-    Function(Function),
+    Function(Rc<Function>),
+    /// A list, shared between every value which refers to it.
+    List(Rc<RefCell<List>>),
+    /// A map, shared between every value which refers to it.
+    Map(Rc<RefCell<Map>>),
     NoneType,
+    /// The value of the local `name` before its `let` has run. It never leaves the local's slot.
+    Undefined(String),
 }
+
+/// A map key. Only ints, bools and strings can be used as keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Int(i64),
+    BigInt(BigInt),
+    Bool(bool),
+    String(String),
+}
+
+impl Key {
+    fn new(val: Types, span: Span) -> Result<Self, RuntimeError> {
+        match val {
+            Types::Int(x) => Ok(Key::Int(x)),
+            Types::BigInt(x) => Ok(Key::BigInt((*x).clone())),
+            Types::Bool(x) => Ok(Key::Bool(x)),
+            Types::String(x) => Ok(Key::String(x)),
+            val => Err(RuntimeError::TypeError {
+                span,
+                msg: format!("{} cannot be used as a map key", val.type_name()),
+            }),
+        }
+    }
+
+    fn value(&self) -> Types {
+        match self {
+            Key::Int(x) => Types::Int(*x),
+            Key::BigInt(x) => Types::BigInt(Rc::new(x.clone())),
+            Key::Bool(x) => Types::Bool(*x),
+            Key::String(x) => Types::String(x.clone()),
+        }
+    }
+}
+
+/// A map which iterates over its entries in the order their keys were first inserted.
+#[derive(Debug, Default)]
+pub struct Map {
+    index: HashMap<Key, usize>,
+    entries: Vec<(Key, Types)>,
+}
+
+impl Map {
+    fn get(&self, key: &Key) -> Option<&Types> {
+        self.index.get(key).map(|&idx| &self.entries[idx].1)
+    }
+
+    fn insert(&mut self, key: Key, val: Types) {
+        match self.index.get(&key) {
+            Some(&idx) => self.entries[idx].1 = val,
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, val));
+            }
+        }
+    }
+}
+impl Drop for Map {
+    fn drop(&mut self) {
+        let entries = mem::take(&mut self.entries);
+        drop_items(entries.into_iter().map(|(_, val)| val).collect());
+    }
+}
+
+/// The items of a list.
+#[derive(Debug, Default)]
+pub struct List(Vec<Types>);
+
+impl Deref for List {
+    type Target = Vec<Types>;
+
+    fn deref(&self) -> &Vec<Types> {
+        &self.0
+    }
+}
+
+impl DerefMut for List {
+    fn deref_mut(&mut self) -> &mut Vec<Types> {
+        &mut self.0
+    }
+}
+
+impl Drop for List {
+    fn drop(&mut self) {
+        drop_items(mem::take(&mut self.0));
+    }
+}
+
+/// Drop `items`. Dropping a container drops its items, so deeply nested containers would
+/// overflow the Rust stack if dropped recursively. Instead, the items of containers with no
+/// other references are moved into `items` before the (now empty) container is dropped.
+fn drop_items(mut items: Vec<Types>) {
+    while let Some(item) = items.pop() {
+        match item {
+            Types::List(xs) => {
+                if let Ok(xs) = Rc::try_unwrap(xs) {
+                    items.append(&mut xs.borrow_mut().0);
+                }
+            }
+            Types::Map(map) => {
+                if let Ok(map) = Rc::try_unwrap(map) {
+                    let entries = mem::take(&mut map.borrow_mut().entries);
+                    items.extend(entries.into_iter().map(|(_, val)| val));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A container being formatted by `Types::pretty_in`.
+enum Container {
+    List(Rc<RefCell<List>>),
+    Map(Rc<RefCell<Map>>),
+}
+
 #[derive(Debug, Clone)]
 pub struct Function {
     pub name: Option<String>,
     pub args: Vec<String>,
-    pub prog: Vec<OpCode>,
+    pub prog: Rc<Bytecode>,
+    /// The variables captured by a closure, shared with the function that created it.
+    pub upvalues: Vec<Rc<RefCell<Types>>>,
 }
 
 impl Function {
-    pub fn new(name: String, locals: Vec<Types>, args: Vec<String>, prog: Vec<OpCode>) -> Self {
+    pub fn new(name: String, args: Vec<String>, prog: Rc<Bytecode>) -> Self {
         Self {
             name: Some(name),
             args,
             prog,
+            upvalues: Vec::new(),
         }
     }
 }
 
+/// A function local. Locals hold their value directly until a closure captures them, at which
+/// point the value moves into a cell shared between the function and the closure.
+#[derive(Debug, Clone)]
+enum Slot {
+    Value(Types),
+    Cell(Rc<RefCell<Types>>),
+}
+
+impl Slot {
+    fn get(&self) -> Types {
+        match self {
+            Slot::Value(val) => val.clone(),
+            Slot::Cell(cell) => cell.borrow().clone(),
+        }
+    }
+
+    fn set(&mut self, val: Types) {
+        match self {
+            Slot::Value(_) => *self = Slot::Value(val),
+            Slot::Cell(cell) => *cell.borrow_mut() = val,
+        }
+    }
+
+    fn capture(&mut self) -> Rc<RefCell<Types>> {
+        if let Slot::Value(val) = self {
+            *self = Slot::Cell(Rc::new(RefCell::new(val.clone())));
+        }
+        match self {
+            Slot::Cell(cell) => Rc::clone(cell),
+            Slot::Value(_) => unreachable!(),
+        }
+    }
+}
+
+fn slot(locals: &mut Vec<Slot>, idx: usize) -> &mut Slot {
+    if idx >= locals.len() {
+        locals.resize(idx + 1, Slot::Value(Types::NoneType));
+    }
+    &mut locals[idx]
+}
+
 impl Types {
+    /// Create a new list holding `items`.
+    fn list(items: Vec<Types>) -> Types {
+        Types::List(Rc::new(RefCell::new(List(items))))
+    }
+
     fn pretty(&self) -> String {
+        self.pretty_in(&mut HashSet::new())
+    }
+
+    /// Format `self`, where `seen` holds the containers currently being formatted. A container
+    /// which (directly or indirectly) contains itself prints as `[...]` or `{...}` where it recurs.
+    /// Containers are formatted with an explicit stack rather than recursively, so that deeply
+    /// nested values cannot overflow the Rust stack.
+    fn pretty_in(&self, seen: &mut HashSet<*const ()>) -> String {
+        let mut out = String::new();
+        // The containers currently being formatted, with the index of their next item.
+        let mut open = Vec::new();
+        self.pretty_start(&mut out, seen, &mut open, false);
+        while let Some((container, idx)) = open.last_mut() {
+            let item = match container {
+                Container::List(xs) => xs.borrow().get(*idx).map(|x| (None, x.clone())),
+                Container::Map(map) => map
+                    .borrow()
+                    .entries
+                    .get(*idx)
+                    .map(|(k, v)| (Some(k.value()), v.clone())),
+            };
+            match item {
+                Some((key, val)) => {
+                    if *idx > 0 {
+                        out.push_str(", ");
+                    }
+                    *idx += 1;
+                    if let Some(key) = key {
+                        key.pretty_start(&mut out, seen, &mut open, true);
+                        out.push_str(": ");
+                    }
+                    val.pretty_start(&mut out, seen, &mut open, true);
+                }
+                None => {
+                    let (ptr, close) = match container {
+                        Container::List(xs) => (Rc::as_ptr(xs) as *const (), ']'),
+                        Container::Map(map) => (Rc::as_ptr(map) as *const (), '}'),
+                    };
+                    out.push(close);
+                    seen.remove(&ptr);
+                    open.pop();
+                }
+            }
+        }
+        out
+    }
+
+    /// Format `self` onto `out`. Scalars are formatted in full, while containers are opened and
+    /// pushed onto `open` for `pretty_in` to format their items. If `item` is true, `self` is an
+    /// item of a container and strings are quoted, so that, for example, `"1"` and `1` print
+    /// differently.
+    fn pretty_start(
+        &self,
+        out: &mut String,
+        seen: &mut HashSet<*const ()>,
+        open: &mut Vec<(Container, usize)>,
+        item: bool,
+    ) {
         match *self {
-            Types::Int(ref x) => x.to_string(),
-            Types::Bool(ref x) => x.to_string(),
-            Types::String(ref x) => x.to_string(),
-            Types::Function(ref x) => todo!(),
-            Types::NoneType => "None".to_string(),
+            Types::Int(ref x) => out.push_str(&x.to_string()),
+            Types::BigInt(ref x) => out.push_str(&x.to_string()),
+            // Debug formatting always includes a `.` or exponent, so floats read back as floats.
+            Types::Float(ref x) => out.push_str(&format!("{:?}", x)),
+            Types::Bool(ref x) => out.push_str(&x.to_string()),
+            Types::String(ref x) if item => out.push_str(&quote(x)),
+            Types::String(ref x) => out.push_str(x),
+            Types::Function(ref x) => match x.name {
+                Some(ref name) => out.push_str(&format!("<function {}>", name)),
+                None => out.push_str("<anonymous function>"),
+            },
+            Types::List(ref xs) => {
+                if seen.insert(Rc::as_ptr(xs) as *const ()) {
+                    out.push('[');
+                    open.push((Container::List(Rc::clone(xs)), 0));
+                } else {
+                    out.push_str("[...]");
+                }
+            }
+            Types::Map(ref map) => {
+                if seen.insert(Rc::as_ptr(map) as *const ()) {
+                    out.push('{');
+                    open.push((Container::Map(Rc::clone(map)), 0));
+                } else {
+                    out.push_str("{...}");
+                }
+            }
+            Types::NoneType => out.push_str("None"),
+            Types::Undefined(_) => out.push_str("<undefined>"),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match *self {
+            Types::Int(_) | Types::BigInt(_) => "int",
+            Types::Float(_) => "float",
+            Types::Bool(_) => "bool",
+            Types::String(_) => "string",
+            Types::Function(_) => "function",
+            Types::List(_) => "list",
+            Types::Map(_) => "map",
+            Types::NoneType => "None",
+            Types::Undefined(_) => "undefined",
         }
     }
 }
 
+/// Render `s` as a string literal which reads back as `s`.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\x08' => quoted.push_str("\\b"),
+            '\x0C' => quoted.push_str("\\f"),
+            '\0' => quoted.push_str("\\0"),
+            '$' if chars.peek() == Some(&'{') => quoted.push_str("\\$"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 impl fmt::Display for Types {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.pretty())
     }
 }
 
-fn vm(
-    prog: Vec<OpCode>,
-    locals: &mut Vec<Types>,
-    functions: &mut Vec<Function>,
-) -> Result<Vec<Types>, String> {
-    if prog.is_empty() {
-        return Err("Cannot execute empty program".to_string());
+#[derive(Debug)]
+pub enum RuntimeError {
+    TypeError {
+        span: Span,
+        msg: String,
+    },
+    ArityError {
+        span: Span,
+        name: Option<String>,
+        expected: usize,
+        found: usize,
+    },
+    UndefinedGlobal {
+        span: Span,
+        name: String,
+    },
+    UndefinedVariable {
+        span: Span,
+        name: String,
+    },
+    NotCallable {
+        span: Span,
+        type_name: &'static str,
+    },
+    IndexOutOfBounds {
+        span: Span,
+        index: String,
+        len: usize,
+    },
+    /// A slice whose end index comes before its start index.
+    InvalidRange {
+        span: Span,
+        start: usize,
+        end: usize,
+    },
+    EmptySeparator {
+        span: Span,
+    },
+    EmptyList {
+        span: Span,
+    },
+    KeyError {
+        span: Span,
+        key: String,
+    },
+    StackUnderflow {
+        span: Span,
+    },
+    DivisionByZero {
+        span: Span,
+    },
+    RecursionLimit {
+        span: Span,
+    },
+}
+
+impl RuntimeError {
+    /// The span of the expression which caused the error.
+    pub fn span(&self) -> Span {
+        match self {
+            RuntimeError::TypeError { span, .. }
+            | RuntimeError::ArityError { span, .. }
+            | RuntimeError::UndefinedGlobal { span, .. }
+            | RuntimeError::UndefinedVariable { span, .. }
+            | RuntimeError::NotCallable { span, .. }
+            | RuntimeError::IndexOutOfBounds { span, .. }
+            | RuntimeError::InvalidRange { span, .. }
+            | RuntimeError::EmptySeparator { span }
+            | RuntimeError::EmptyList { span }
+            | RuntimeError::KeyError { span, .. }
+            | RuntimeError::StackUnderflow { span }
+            | RuntimeError::DivisionByZero { span }
+            | RuntimeError::RecursionLimit { span } => *span,
+        }
     }
-    let mut pc = 0;
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::TypeError { msg, .. } => write!(f, "Type error: {}", msg),
+            RuntimeError::ArityError {
+                name: Some(name),
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "Incorrect number of arguments. '{}' expects {} arguments, but {} were provided.",
+                name, expected, found
+            ),
+            RuntimeError::ArityError {
+                name: None,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "Incorrect number of arguments. Expected {} arguments, but {} were provided.",
+                expected, found
+            ),
+            RuntimeError::UndefinedGlobal { name, .. } => {
+                write!(f, "Global variable '{}' used before it was defined", name)
+            }
+            RuntimeError::UndefinedVariable { name, .. } => {
+                write!(f, "Variable '{}' used before it was defined", name)
+            }
+            RuntimeError::IndexOutOfBounds { index, len, .. } => {
+                write!(f, "Index {} out of range for length {}", index, len)
+            }
+            RuntimeError::InvalidRange { start, end, .. } => {
+                write!(f, "End index {} is before start index {}", end, start)
+            }
+            RuntimeError::EmptySeparator { .. } => write!(f, "Cannot split on an empty separator"),
+            RuntimeError::EmptyList { .. } => write!(f, "Cannot pop from an empty list"),
+            RuntimeError::KeyError { key, .. } => write!(f, "Key '{}' not found in map", key),
+            RuntimeError::NotCallable { type_name, .. } => {
+                write!(f, "Cannot call a value of type {}", type_name)
+            }
+            RuntimeError::StackUnderflow { .. } => write!(f, "Stack underflow"),
+            RuntimeError::DivisionByZero { .. } => write!(f, "Division by zero"),
+            RuntimeError::RecursionLimit { .. } => {
+                write!(f, "Maximum call depth of {} exceeded", MAX_CALL_DEPTH)
+            }
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<Types>, span: Span) -> Result<Types, RuntimeError> {
+    stack.pop().ok_or(RuntimeError::StackUnderflow { span })
+}
+
+/// Check that `val`, loaded from a local or upvalue, has been defined.
+fn defined(val: Types, span: Span) -> Result<Types, RuntimeError> {
+    match val {
+        Types::Undefined(name) => Err(RuntimeError::UndefinedVariable { span, name }),
+        val => Ok(val),
+    }
+}
+
+fn binop_type_error(op: &str, lhs: &Types, rhs: &Types, span: Span) -> RuntimeError {
+    RuntimeError::TypeError {
+        span,
+        msg: format!(
+            "unsupported operand types for {}: {} and {}",
+            op,
+            lhs.type_name(),
+            rhs.type_name()
+        ),
+    }
+}
+
+/// If `lhs` and `rhs` are both numbers, and at least one of them is a float, convert both to
+/// floats.
+fn float_operands(lhs: &Types, rhs: &Types) -> Option<(f64, f64)> {
+    let as_float = |val: &Types| match val {
+        Types::Int(x) => Some(*x as f64),
+        Types::BigInt(x) => Some(x.to_f64()),
+        Types::Float(x) => Some(*x),
+        _ => None,
+    };
+    if !matches!(lhs, Types::Float(_)) && !matches!(rhs, Types::Float(_)) {
+        return None;
+    }
+    Some((as_float(lhs)?, as_float(rhs)?))
+}
+
+/// If `lhs` and `rhs` are both integers, convert both to big integers.
+fn big_operands(lhs: &Types, rhs: &Types) -> Option<(BigInt, BigInt)> {
+    let as_big = |val: &Types| match val {
+        Types::Int(x) => Some(BigInt::from(*x)),
+        Types::BigInt(x) => Some((**x).clone()),
+        _ => None,
+    };
+    Some((as_big(lhs)?, as_big(rhs)?))
+}
+
+/// Are `lhs` and `rhs` equal? Values of different types never are, except for numbers. Lists and
+/// maps are compared item by item without recursion, and functions by identity.
+fn values_eq(lhs: &Types, rhs: &Types) -> bool {
+    let mut todo = vec![(lhs.clone(), rhs.clone())];
+    // Containers reached again through a cycle are assumed to be equal.
+    let mut seen = HashSet::new();
+    while let Some(pair) = todo.pop() {
+        match pair {
+            (Types::List(xs), Types::List(ys)) => {
+                if !seen.insert((Rc::as_ptr(&xs) as *const (), Rc::as_ptr(&ys) as *const ())) {
+                    continue;
+                }
+                let (xs, ys) = (xs.borrow(), ys.borrow());
+                if xs.len() != ys.len() {
+                    return false;
+                }
+                todo.extend(xs.iter().cloned().zip(ys.iter().cloned()));
+            }
+            (Types::Map(x), Types::Map(y)) => {
+                if !seen.insert((Rc::as_ptr(&x) as *const (), Rc::as_ptr(&y) as *const ())) {
+                    continue;
+                }
+                let (x, y) = (x.borrow(), y.borrow());
+                if x.entries.len() != y.entries.len() {
+                    return false;
+                }
+                for (key, val) in &x.entries {
+                    match y.get(key) {
+                        Some(other) => todo.push((val.clone(), other.clone())),
+                        None => return false,
+                    }
+                }
+            }
+            (Types::Int(x), Types::Int(y)) => {
+                if x != y {
+                    return false;
+                }
+            }
+            (Types::Bool(x), Types::Bool(y)) => {
+                if x != y {
+                    return false;
+                }
+            }
+            (Types::String(x), Types::String(y)) => {
+                if x != y {
+                    return false;
+                }
+            }
+            (Types::Function(f), Types::Function(g)) => {
+                if !Rc::ptr_eq(&f, &g) {
+                    return false;
+                }
+            }
+            (Types::NoneType, Types::NoneType) => (),
+            (lhs, rhs) => {
+                let eq = match (big_operands(&lhs, &rhs), float_operands(&lhs, &rhs)) {
+                    (Some((x, y)), _) => x == y,
+                    (_, Some((x, y))) => x == y,
+                    _ => false,
+                };
+                if !eq {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Apply the arithmetic operator `op` to big integers. Division by zero must already have been
+/// ruled out.
+fn big_arith(op: &OpCode, x: &BigInt, y: &BigInt) -> BigInt {
+    match op {
+        OpCode::Plus => x.add(y),
+        OpCode::Minus => x.sub(y),
+        OpCode::Mul => x.mul(y),
+        OpCode::Div => x.div_rem(y).0,
+        _ => x.div_rem(y).1,
+    }
+}
+
+/// Wrap `x`, only keeping it as a big integer if it does not fit in an `Int`.
+fn int_value(x: BigInt) -> Types {
+    match x.to_i64() {
+        Some(x) => Types::Int(x),
+        None => Types::BigInt(Rc::new(x)),
+    }
+}
+
+/// Check that `val` is a function, returning it.
+fn as_function(val: Types, span: Span) -> Result<Rc<Function>, RuntimeError> {
+    match val {
+        Types::Function(func) => Ok(func),
+        val => Err(RuntimeError::NotCallable {
+            span,
+            type_name: val.type_name(),
+        }),
+    }
+}
+
+/// Check that `val` is a list, returning it.
+fn as_list(val: Types, span: Span) -> Result<Rc<RefCell<List>>, RuntimeError> {
+    match val {
+        Types::List(xs) => Ok(xs),
+        val => Err(RuntimeError::TypeError {
+            span,
+            msg: format!("expected a list, found {}", val.type_name()),
+        }),
+    }
+}
+
+/// Check that `val` is a string, returning it.
+fn as_string(val: Types, span: Span) -> Result<String, RuntimeError> {
+    match val {
+        Types::String(x) => Ok(x),
+        val => Err(RuntimeError::TypeError {
+            span,
+            msg: format!("expected a string, found {}", val.type_name()),
+        }),
+    }
+}
+
+/// Check that `val` is a map, returning it.
+fn as_map(val: Types, span: Span) -> Result<Rc<RefCell<Map>>, RuntimeError> {
+    match val {
+        Types::Map(map) => Ok(map),
+        val => Err(RuntimeError::TypeError {
+            span,
+            msg: format!("expected a map, found {}", val.type_name()),
+        }),
+    }
+}
+
+/// The error for indexing into `val`, which is neither a list nor a map.
+fn index_type_error(val: &Types, span: Span) -> RuntimeError {
+    RuntimeError::TypeError {
+        span,
+        msg: format!("{} cannot be indexed", val.type_name()),
+    }
+}
+
+/// Convert `index` into a position within a list of length `len`.
+fn list_index(index: Types, len: usize, span: Span) -> Result<usize, RuntimeError> {
+    match index {
+        Types::Int(i) if i >= 0 && (i as usize) < len => Ok(i as usize),
+        Types::Int(_) | Types::BigInt(_) => Err(RuntimeError::IndexOutOfBounds {
+            span,
+            index: index.pretty(),
+            len,
+        }),
+        index => Err(RuntimeError::TypeError {
+            span,
+            msg: format!("list indices must be int, not {}", index.type_name()),
+        }),
+    }
+}
+
+/// Run the builtin function `name` on `args`, which have already been checked to be of the
+/// right number.
+fn call_builtin(name: &str, mut args: Vec<Types>, span: Span) -> Result<Types, RuntimeError> {
+    match name {
+        "len" => match args.remove(0) {
+            Types::String(x) => Ok(Types::Int(x.chars().count() as i64)),
+            Types::Map(map) => Ok(Types::Int(map.borrow().entries.len() as i64)),
+            Types::List(xs) => Ok(Types::Int(xs.borrow().len() as i64)),
+            val => Err(RuntimeError::TypeError {
+                span,
+                msg: format!("expected a list, string or map, found {}", val.type_name()),
+            }),
+        },
+        "push" => {
+            let val = args.pop().unwrap();
+            as_list(args.remove(0), span)?.borrow_mut().push(val);
+            Ok(Types::NoneType)
+        }
+        "pop" => as_list(args.remove(0), span)?
+            .borrow_mut()
+            .pop()
+            .ok_or(RuntimeError::EmptyList { span }),
+        "keys" => {
+            let map = as_map(args.remove(0), span)?;
+            let keys = map
+                .borrow()
+                .entries
+                .iter()
+                .map(|(k, _)| k.value())
+                .collect();
+            Ok(Types::list(keys))
+        }
+        "values" => {
+            let map = as_map(args.remove(0), span)?;
+            let vals = map
+                .borrow()
+                .entries
+                .iter()
+                .map(|(_, v)| v.clone())
+                .collect();
+            Ok(Types::list(vals))
+        }
+        "has" => {
+            let key = Key::new(args.pop().unwrap(), span)?;
+            let map = as_map(args.remove(0), span)?;
+            let has = map.borrow().get(&key).is_some();
+            Ok(Types::Bool(has))
+        }
+        "substr" => {
+            let end = args.pop().unwrap();
+            let start = args.pop().unwrap();
+            let chars = as_string(args.remove(0), span)?.chars().collect::<Vec<_>>();
+            let len = chars.len();
+            // Both bounds may be equal to the length of the string.
+            let bound = |val: Types| match val {
+                Types::Int(i) if i >= 0 && i as usize <= len => Ok(i as usize),
+                Types::Int(_) | Types::BigInt(_) => Err(RuntimeError::IndexOutOfBounds {
+                    span,
+                    index: val.pretty(),
+                    len,
+                }),
+                val => Err(RuntimeError::TypeError {
+                    span,
+                    msg: format!("string indices must be int, not {}", val.type_name()),
+                }),
+            };
+            let start = bound(start)?;
+            let end = bound(end)?;
+            if end < start {
+                return Err(RuntimeError::InvalidRange { span, start, end });
+            }
+            Ok(Types::String(chars[start..end].iter().collect()))
+        }
+        "split" => {
+            let sep = as_string(args.pop().unwrap(), span)?;
+            let x = as_string(args.remove(0), span)?;
+            if sep.is_empty() {
+                return Err(RuntimeError::EmptySeparator { span });
+            }
+            let parts = x
+                .split(&sep)
+                .map(|p| Types::String(p.to_string()))
+                .collect();
+            Ok(Types::list(parts))
+        }
+        "join" => {
+            let sep = as_string(args.pop().unwrap(), span)?;
+            let xs = as_list(args.remove(0), span)?;
+            let mut seen = HashSet::from([Rc::as_ptr(&xs) as *const ()]);
+            let joined = xs
+                .borrow()
+                .iter()
+                .map(|x| x.pretty_in(&mut seen))
+                .collect::<Vec<_>>()
+                .join(&sep);
+            Ok(Types::String(joined))
+        }
+        "upper" => Ok(Types::String(
+            as_string(args.remove(0), span)?.to_uppercase(),
+        )),
+        "lower" => Ok(Types::String(
+            as_string(args.remove(0), span)?.to_lowercase(),
+        )),
+        "trim" => Ok(Types::String(
+            as_string(args.remove(0), span)?.trim().to_string(),
+        )),
+        "contains" => {
+            let sub = as_string(args.pop().unwrap(), span)?;
+            let x = as_string(args.remove(0), span)?;
+            Ok(Types::Bool(x.contains(&sub)))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// The number of arguments taken by the builtin function `name`.
+fn builtin_arity(name: &str) -> usize {
+    match name {
+        "push" | "has" | "split" | "join" | "contains" => 2,
+        "substr" => 3,
+        _ => 1,
+    }
+}
+
+/// The state of a caller, saved while one of its callees runs.
+struct Frame {
+    func: Rc<Function>,
+    pc: usize,
+    stack_base: usize,
+    locals_base: usize,
+}
+
+/// The maximum number of nested function calls, so that unbounded recursion is reported as an
+/// error rather than exhausting memory.
+const MAX_CALL_DEPTH: usize = 10_000;
+
+fn vm(main: Function) -> Result<Vec<Types>, RuntimeError> {
+    // Every function call shares `stack` and `locals`: the running function owns everything
+    // above `stack_base` and `locals_base` respectively. Arguments are moved in order from the
+    // caller's stack into the callee's first locals.
     let mut stack: Vec<Types> = Vec::new();
+    let mut locals: Vec<Slot> = Vec::new();
+    let mut frames: Vec<Frame> = Vec::new();
+    // Top-level variables, shared by every function.
+    let mut globals: HashMap<String, Types> = HashMap::new();
+    let mut func = Rc::new(main);
+    let mut pc = 0;
+    let mut stack_base = 0;
+    let mut locals_base = 0;
 
-    while pc < prog.len() {
-        let expr = &prog[pc];
-        match &*expr {
+    loop {
+        if pc >= func.prog.len() {
+            // Falling off the end of a function returns `None` to its caller.
+            debug_assert_eq!(
+                stack.len(),
+                stack_base,
+                "operand stack not empty at end of function"
+            );
+            match frames.pop() {
+                Some(frame) => {
+                    stack.truncate(stack_base);
+                    locals.truncate(locals_base);
+                    func = frame.func;
+                    pc = frame.pc;
+                    stack_base = frame.stack_base;
+                    locals_base = frame.locals_base;
+                    stack.push(Types::NoneType);
+                    continue;
+                }
+                None => break,
+            }
+        }
+        let expr = &func.prog[pc];
+        let span = func.prog.spans[pc];
+        match expr {
+            OpCode::PushBigInt(ref x) => {
+                stack.push(Types::BigInt(Rc::clone(x)));
+                pc += 1;
+            }
             OpCode::PushInt(ref x) => {
                 stack.push(Types::Int(*x));
                 pc += 1;
             }
+            OpCode::PushFloat(ref x) => {
+                stack.push(Types::Float(*x));
+                pc += 1;
+            }
             OpCode::PushStr(ref x) => {
                 stack.push(Types::String(x.clone()));
                 pc += 1;
             }
-            //how to optimize this function?
+            OpCode::PushBool(ref x) => {
+                stack.push(Types::Bool(*x));
+                pc += 1;
+            }
+            OpCode::PushNone => {
+                stack.push(Types::NoneType);
+                pc += 1;
+            }
+            OpCode::PushUndefined(ref name) => {
+                stack.push(Types::Undefined(name.clone()));
+                pc += 1;
+            }
+            OpCode::DefineVar(ref idx) => {
+                // A declaration always creates a fresh binding, leaving any closures which
+                // captured a previous binding in this slot untouched.
+                let val = pop(&mut stack, span)?;
+                *slot(&mut locals, locals_base + *idx) = Slot::Value(val);
+                pc += 1;
+            }
             OpCode::StoreVar(ref idx) => {
-                //change here to handle default cases foe each type
-                let len = locals.len();
-                if let Some(val) = stack.pop() {
-                    let cval = val.to_owned();
-                    match val {
-                        Types::Int(_) | Types::String(_) | Types::Function(_) => {
-                            if *idx < len {
-                                locals[*idx] = cval;
-                            } else {
-                                locals.push(cval);
-                            }
-                        }
-                        Types::Bool(_) => todo!(),
-                        Types::NoneType => panic!("type not implemented yet!"),
+                let val = pop(&mut stack, span)?;
+                slot(&mut locals, locals_base + *idx).set(val);
+                pc += 1;
+            }
+            OpCode::LoadVar(ref idx) => {
+                let val = locals
+                    .get(locals_base + *idx)
+                    .map_or(Types::NoneType, Slot::get);
+                stack.push(defined(val, span)?);
+                pc += 1;
+            }
+            OpCode::StoreUpvalue(ref idx) => {
+                let val = pop(&mut stack, span)?;
+                *func.upvalues[*idx].borrow_mut() = val;
+                pc += 1;
+            }
+            OpCode::LoadUpvalue(ref idx) => {
+                let val = func.upvalues[*idx].borrow().clone();
+                stack.push(defined(val, span)?);
+                pc += 1;
+            }
+            OpCode::StoreGlobal(ref name) => {
+                let val = pop(&mut stack, span)?;
+                globals.insert(name.clone(), val);
+                pc += 1;
+            }
+            OpCode::LoadGlobal(ref name) => match globals.get(name) {
+                Some(val) => {
+                    stack.push(val.clone());
+                    pc += 1;
+                }
+                None => {
+                    return Err(RuntimeError::UndefinedGlobal {
+                        span,
+                        name: name.clone(),
+                    })
+                }
+            },
+            OpCode::ToString => {
+                let val = pop(&mut stack, span)?;
+                stack.push(Types::String(val.pretty()));
+                pc += 1;
+            }
+            OpCode::ToList => {
+                let items = match pop(&mut stack, span)? {
+                    val @ Types::List(_) => val,
+                    Types::Map(map) => {
+                        let keys = map
+                            .borrow()
+                            .entries
+                            .iter()
+                            .map(|(k, _)| k.value())
+                            .collect();
+                        Types::list(keys)
+                    }
+                    Types::String(s) => {
+                        let chars = s.chars().map(|c| Types::String(c.to_string())).collect();
+                        Types::list(chars)
                     }
+                    val => {
+                        return Err(RuntimeError::TypeError {
+                            span,
+                            msg: format!("cannot iterate over {}", val.type_name()),
+                        })
+                    }
+                };
+                stack.push(items);
+                pc += 1;
+            }
+            OpCode::CheckInt => {
+                match stack.last() {
+                    Some(Types::Int(_) | Types::BigInt(_)) => (),
+                    Some(val) => {
+                        return Err(RuntimeError::TypeError {
+                            span,
+                            msg: format!("range bounds must be int, not {}", val.type_name()),
+                        })
+                    }
+                    None => return Err(RuntimeError::StackUnderflow { span }),
                 }
                 pc += 1;
             }
-            OpCode::LoadVar(ref idx) => {
-                let val = locals[*idx].clone();
-                stack.push(val);
+            OpCode::Concat(ref n) => {
+                if stack.len() < stack_base + n {
+                    return Err(RuntimeError::StackUnderflow { span });
+                }
+                let parts = stack.split_off(stack.len() - n);
+                stack.push(Types::String(parts.iter().map(Types::pretty).collect()));
                 pc += 1;
             }
-            OpCode::Call(ct) => {
-                match ct {
-                    CallTarget::Func(label, args_len) => {
-                        if let Some(index) =
-                            functions.iter().position(|f| f.name == Some(label.clone()))
-                        {
-                            let mut func = &functions[index];
-                            if *args_len != func.args.len() {
-                                println!("Error: Incorrect number of arguments. '{}' expects {} arguments, but {} were provided.", label, func.args.len(), args_len);
-                                panic!();
-                            }
-                            let mut localsnew = Vec::new();
-                            for _ in 0..func.args.len() {
-                                let val = stack.pop().unwrap();
-                                localsnew.push(val);
+            OpCode::BuildList(ref n) => {
+                if stack.len() < stack_base + n {
+                    return Err(RuntimeError::StackUnderflow { span });
+                }
+                let items = stack.split_off(stack.len() - n);
+                stack.push(Types::list(items));
+                pc += 1;
+            }
+            OpCode::BuildMap(ref n) => {
+                if stack.len() < stack_base + 2 * n {
+                    return Err(RuntimeError::StackUnderflow { span });
+                }
+                let mut map = Map::default();
+                let mut entries = stack.split_off(stack.len() - 2 * n).into_iter();
+                while let (Some(key), Some(val)) = (entries.next(), entries.next()) {
+                    map.insert(Key::new(key, span)?, val);
+                }
+                stack.push(Types::Map(Rc::new(RefCell::new(map))));
+                pc += 1;
+            }
+            OpCode::LoadIndex => {
+                let index = pop(&mut stack, span)?;
+                let val = match pop(&mut stack, span)? {
+                    Types::List(xs) => {
+                        let xs = xs.borrow();
+                        xs[list_index(index, xs.len(), span)?].clone()
+                    }
+                    Types::Map(map) => {
+                        let key = Key::new(index, span)?;
+                        match map.borrow().get(&key) {
+                            Some(val) => val.clone(),
+                            None => {
+                                return Err(RuntimeError::KeyError {
+                                    span,
+                                    key: key.value().pretty(),
+                                })
                             }
-                            let result = vm(func.prog.clone(), &mut localsnew, functions)?;
-                            stack.extend(result);
-                        } else {
-                            return Err(format!("Function '{}' not found", label));
                         }
                     }
-
+                    val => return Err(index_type_error(&val, span)),
+                };
+                stack.push(val);
+                pc += 1;
+            }
+            OpCode::StoreIndex => {
+                let val = pop(&mut stack, span)?;
+                let index = pop(&mut stack, span)?;
+                match pop(&mut stack, span)? {
+                    Types::List(xs) => {
+                        let mut xs = xs.borrow_mut();
+                        let idx = list_index(index, xs.len(), span)?;
+                        xs[idx] = val;
+                    }
+                    Types::Map(map) => map.borrow_mut().insert(Key::new(index, span)?, val),
+                    container => return Err(index_type_error(&container, span)),
+                }
+                pc += 1;
+            }
+            OpCode::Call(ct) => {
+                let (callee, args_len) = match ct {
                     CallTarget::Var(index, args_len) => {
-                        let func_value = locals.get_mut(*index).unwrap();
-                        if let Types::Function(func) = func_value {
-                            let func_prog = func.prog.clone();
-                            if *args_len != func.args.len() {
-                                println!("Error: Incorrect number of arguments. Expected {} arguments, but {} were provided.", func.args.len(), args_len);
-                                panic!();
-                            }
-                            let mut localsnew = Vec::new();
-                            for _ in 0..func.args.len() {
-                                let val = stack.pop().unwrap();
-                                localsnew.push(val);
-                            }
-                            let result = vm(func_prog, &mut localsnew, functions)?;
-                            stack.extend(result);
+                        let val = locals
+                            .get(locals_base + *index)
+                            .map_or(Types::NoneType, Slot::get);
+                        (as_function(defined(val, span)?, span)?, *args_len)
+                    }
+                    CallTarget::Upvalue(index, args_len) => {
+                        let val = func.upvalues[*index].borrow().clone();
+                        (as_function(defined(val, span)?, span)?, *args_len)
+                    }
+                    CallTarget::Value(args_len) => {
+                        if stack.len() < stack_base + args_len + 1 {
+                            return Err(RuntimeError::StackUnderflow { span });
                         }
+                        let val = stack.remove(stack.len() - args_len - 1);
+                        (as_function(val, span)?, *args_len)
                     }
-                    CallTarget::Builtins(label) => {
+                    CallTarget::Global(name, args_len) => match globals.get(name) {
+                        Some(val) => (as_function(val.clone(), span)?, *args_len),
+                        None => {
+                            return Err(RuntimeError::UndefinedGlobal {
+                                span,
+                                name: name.clone(),
+                            })
+                        }
+                    },
+                    CallTarget::Builtins(label, args_len) => {
                         if label == "print" {
                             // execute the built-in function
-                            let mut output = String::new();
-
-                            if let Some(val) = stack.pop() {
-                                match val {
-                                    Types::Int(x) => output.push_str(&x.to_string()),
-                                    Types::Bool(x) => output.push_str(&x.to_string()),
-                                    Types::String(x) => output.push_str(&x),
-                                    Types::Function(x) => {
-                                        panic!("Doesn't support function parsing in print.")
-                                    }
-                                    Types::NoneType => output.push_str("None"),
-                                }
+                            let val = pop(&mut stack, span)?;
+                            println!("{}", val);
+                        } else {
+                            let expected = builtin_arity(label);
+                            if *args_len != expected {
+                                return Err(RuntimeError::ArityError {
+                                    span,
+                                    name: Some(label.clone()),
+                                    expected,
+                                    found: *args_len,
+                                });
                             }
-
-                            println!("{}", output);
+                            if stack.len() < stack_base + args_len {
+                                return Err(RuntimeError::StackUnderflow { span });
+                            }
+                            let args = stack.split_off(stack.len() - args_len);
+                            stack.push(call_builtin(label, args, span)?);
                         }
+                        pc += 1;
+                        continue;
                     }
+                };
+                if args_len != callee.args.len() {
+                    return Err(RuntimeError::ArityError {
+                        span,
+                        name: callee.name.clone(),
+                        expected: callee.args.len(),
+                        found: args_len,
+                    });
                 }
-                pc += 1;
+                if stack.len() < stack_base + args_len {
+                    return Err(RuntimeError::StackUnderflow { span });
+                }
+                if frames.len() == MAX_CALL_DEPTH {
+                    return Err(RuntimeError::RecursionLimit { span });
+                }
+                let args = stack.split_off(stack.len() - args_len);
+                frames.push(Frame {
+                    func: std::mem::replace(&mut func, callee),
+                    pc: pc + 1,
+                    stack_base,
+                    locals_base,
+                });
+                pc = 0;
+                stack_base = stack.len();
+                locals_base = locals.len();
+                locals.extend(args.into_iter().map(Slot::Value));
             }
-            OpCode::Plus => {
-                let rhs = stack.pop().unwrap();
-                let lhs = stack.pop().unwrap();
+            OpCode::Plus | OpCode::Minus | OpCode::Mul => {
+                let rhs = pop(&mut stack, span)?;
+                let lhs = pop(&mut stack, span)?;
                 match (lhs, rhs) {
-                    (Types::Int(x), Types::Int(y)) => stack.push(Types::Int(x + y)),
-                    _ => panic!("TypeError"),
+                    (Types::String(x), Types::String(y)) if matches!(expr, OpCode::Plus) => {
+                        stack.push(Types::String(x + &y))
+                    }
+                    (Types::Int(x), Types::Int(y)) => {
+                        let res = match expr {
+                            OpCode::Plus => x.checked_add(y),
+                            OpCode::Minus => x.checked_sub(y),
+                            _ => x.checked_mul(y),
+                        };
+                        // Results which overflow are promoted to big integers.
+                        stack.push(match res {
+                            Some(res) => Types::Int(res),
+                            None => int_value(big_arith(expr, &x.into(), &y.into())),
+                        });
+                    }
+                    (lhs, rhs) => match (big_operands(&lhs, &rhs), float_operands(&lhs, &rhs)) {
+                        (Some((x, y)), _) => stack.push(int_value(big_arith(expr, &x, &y))),
+                        (_, Some((x, y))) => stack.push(Types::Float(match expr {
+                            OpCode::Plus => x + y,
+                            OpCode::Minus => x - y,
+                            _ => x * y,
+                        })),
+                        _ => {
+                            let op = match expr {
+                                OpCode::Plus => "+",
+                                OpCode::Minus => "-",
+                                _ => "*",
+                            };
+                            return Err(binop_type_error(op, &lhs, &rhs, span));
+                        }
+                    },
                 }
                 pc += 1;
             }
-            OpCode::Minus => {
-                let rhs = stack.pop().unwrap();
-                let lhs = stack.pop().unwrap();
+            OpCode::Div | OpCode::Mod => {
+                let rhs = pop(&mut stack, span)?;
+                let lhs = pop(&mut stack, span)?;
                 match (lhs, rhs) {
-                    (Types::Int(x), Types::Int(y)) => stack.push(Types::Int(x - y)),
-                    _ => panic!("TypeError"),
+                    (Types::Int(_), Types::Int(0)) => {
+                        return Err(RuntimeError::DivisionByZero { span });
+                    }
+                    (Types::Int(x), Types::Int(y)) => {
+                        // Only `i64::MIN / -1` can overflow.
+                        let res = if let OpCode::Div = expr {
+                            x.checked_div(y)
+                        } else {
+                            x.checked_rem(y)
+                        };
+                        stack.push(match res {
+                            Some(res) => Types::Int(res),
+                            None => int_value(big_arith(expr, &x.into(), &y.into())),
+                        });
+                    }
+                    (lhs, rhs) => match (big_operands(&lhs, &rhs), float_operands(&lhs, &rhs)) {
+                        (Some((_, y)), _) if y.is_zero() => {
+                            return Err(RuntimeError::DivisionByZero { span });
+                        }
+                        (Some((x, y)), _) => stack.push(int_value(big_arith(expr, &x, &y))),
+                        (_, Some((_, 0.0))) => {
+                            return Err(RuntimeError::DivisionByZero { span });
+                        }
+                        (_, Some((x, y))) => {
+                            if let OpCode::Div = expr {
+                                stack.push(Types::Float(x / y));
+                            } else {
+                                stack.push(Types::Float(x % y));
+                            }
+                        }
+                        _ => {
+                            let op = if let OpCode::Div = expr { "/" } else { "%" };
+                            return Err(binop_type_error(op, &lhs, &rhs, span));
+                        }
+                    },
                 }
                 pc += 1;
             }
-            OpCode::Eqeq => {
-                if let (Some(rhs), Some(lhs)) = (stack.pop(), stack.pop()) {
-                    if let (Types::Int(lhs_val), Types::Int(rhs_val)) = (lhs, rhs) {
-                        stack.push(Types::Bool(lhs_val == rhs_val));
-                    } else {
-                        return Err("Cannot compare values of different types".to_string());
-                    }
+            OpCode::Eqeq | OpCode::Noteq => {
+                let rhs = pop(&mut stack, span)?;
+                let lhs = pop(&mut stack, span)?;
+                let eq = values_eq(&lhs, &rhs);
+                if let OpCode::Eqeq = expr {
+                    stack.push(Types::Bool(eq));
                 } else {
-                    return Err("Cannot compare values on empty stack".to_string());
+                    stack.push(Types::Bool(!eq));
                 }
                 pc += 1;
             }
-            OpCode::Lteq => {
-                if let (Some(rhs), Some(lhs)) = (stack.pop(), stack.pop()) {
-                    if let (Types::Int(lhs_val), Types::Int(rhs_val)) = (lhs, rhs) {
-                        stack.push(Types::Bool(lhs_val <= rhs_val));
-                    } else {
-                        return Err("Cannot compare values of different types".to_string());
+            OpCode::Lt | OpCode::Lteq | OpCode::Gt | OpCode::Gteq => {
+                let rhs = pop(&mut stack, span)?;
+                let lhs = pop(&mut stack, span)?;
+                // Comparisons involving NaN have no ordering, and so are always false.
+                let ord = match (lhs, rhs) {
+                    (Types::Int(x), Types::Int(y)) => Some(x.cmp(&y)),
+                    (Types::String(x), Types::String(y)) => Some(x.cmp(&y)),
+                    (lhs, rhs) => match (big_operands(&lhs, &rhs), float_operands(&lhs, &rhs)) {
+                        (Some((x, y)), _) => Some(x.cmp(&y)),
+                        (_, Some((x, y))) => x.partial_cmp(&y),
+                        _ => {
+                            let op = match expr {
+                                OpCode::Lt => "<",
+                                OpCode::Lteq => "<=",
+                                OpCode::Gt => ">",
+                                _ => ">=",
+                            };
+                            return Err(binop_type_error(op, &lhs, &rhs, span));
+                        }
+                    },
+                };
+                stack.push(Types::Bool(match expr {
+                    OpCode::Lt => ord.is_some_and(Ordering::is_lt),
+                    OpCode::Lteq => ord.is_some_and(Ordering::is_le),
+                    OpCode::Gt => ord.is_some_and(Ordering::is_gt),
+                    _ => ord.is_some_and(Ordering::is_ge),
+                }));
+                pc += 1;
+            }
+            OpCode::Not => {
+                match pop(&mut stack, span)? {
+                    Types::Bool(x) => stack.push(Types::Bool(!x)),
+                    val => {
+                        return Err(RuntimeError::TypeError {
+                            span,
+                            msg: format!("cannot apply '!' to {}", val.type_name()),
+                        })
                     }
-                } else {
-                    return Err("Cannot compare values on empty stack".to_string());
                 }
                 pc += 1;
             }
-
-            OpCode::Lt => {
-                if let (Some(ref rhs), Some(ref lhs)) = (stack.pop(), stack.pop()) {
-                    if let (Types::Int(lhs_val), Types::Int(rhs_val)) = (lhs, rhs) {
-                        stack.push(Types::Bool(lhs_val < rhs_val));
-                    } else {
-                        return Err("Cannot compare values of different types".to_string());
+            OpCode::Neg => {
+                match pop(&mut stack, span)? {
+                    Types::Int(x) => stack.push(match x.checked_neg() {
+                        Some(x) => Types::Int(x),
+                        None => int_value(BigInt::from(x).neg()),
+                    }),
+                    Types::BigInt(x) => stack.push(int_value(x.neg())),
+                    Types::Float(x) => stack.push(Types::Float(-x)),
+                    val => {
+                        return Err(RuntimeError::TypeError {
+                            span,
+                            msg: format!("cannot apply '-' to {}", val.type_name()),
+                        })
                     }
-                } else {
-                    return Err("Cannot compare values on empty stack".to_string());
                 }
                 pc += 1;
             }
-
             OpCode::Jump(pos) => {
+                // Loops jump backwards between statements, where the operand stack must be empty.
+                debug_assert!(
+                    *pos > pc || stack.len() == stack_base,
+                    "operand stack not empty at loop back edge"
+                );
                 pc = *pos;
             }
-            OpCode::JumpIfFalse(pos) => {
-                let val = stack.pop().unwrap();
-
-                if let Types::Bool(false) = val {
-                    pc = *pos;
-                } else {
-                    pc += 1;
+            OpCode::JumpIfFalse(pos) => match pop(&mut stack, span)? {
+                Types::Bool(true) => pc += 1,
+                Types::Bool(false) => pc = *pos,
+                val => {
+                    return Err(RuntimeError::TypeError {
+                        span,
+                        msg: format!("condition must be bool, not {}", val.type_name()),
+                    })
+                }
+            },
+            OpCode::JumpIfFalseOrPop(pos) | OpCode::JumpIfTrueOrPop(pos) => {
+                let jump_on = matches!(expr, OpCode::JumpIfTrueOrPop(_));
+                match stack.last() {
+                    Some(Types::Bool(x)) if *x == jump_on => pc = *pos,
+                    Some(Types::Bool(_)) => {
+                        stack.pop();
+                        pc += 1;
+                    }
+                    Some(val) => {
+                        let op = if jump_on { "||" } else { "&&" };
+                        return Err(RuntimeError::TypeError {
+                            span,
+                            msg: format!("cannot apply '{}' to {}", op, val.type_name()),
+                        });
+                    }
+                    None => return Err(RuntimeError::StackUnderflow { span }),
                 }
             }
 
             OpCode::Return => {
-                return Ok(stack);
+                debug_assert_eq!(
+                    stack.len(),
+                    stack_base + 1,
+                    "operand stack holds more than the return value"
+                );
+                let val = pop(&mut stack, span)?;
+                match frames.pop() {
+                    Some(frame) => {
+                        stack.truncate(stack_base);
+                        locals.truncate(locals_base);
+                        func = frame.func;
+                        pc = frame.pc;
+                        stack_base = frame.stack_base;
+                        locals_base = frame.locals_base;
+                        stack.push(val);
+                    }
+                    None => {
+                        stack.push(val);
+                        break;
+                    }
+                }
             }
-            OpCode::InlineFunc(args, func_prog, locals) => {
-                let func = Function {
-                    name: None,
+            OpCode::InlineFunc(name, args, func_prog, captures) => {
+                let closure = Function {
+                    name: name.clone(),
                     args: args.to_vec(),
-                    prog: func_prog.to_vec(),
+                    prog: func_prog.clone(),
+                    upvalues: captures
+                        .iter()
+                        .map(|c| match c {
+                            Capture::Local(idx) => slot(&mut locals, locals_base + *idx).capture(),
+                            Capture::Upvalue(idx) => Rc::clone(&func.upvalues[*idx]),
+                        })
+                        .collect(),
                 };
-                stack.push(Types::Function(func));
+                stack.push(Types::Function(Rc::new(closure)));
                 pc += 1;
             }
-            OpCode::DefineFunc(name, args, func_prog, locals) => {
-                let mut func_locals = Vec::new();
-                for local in locals.iter() {
-                    func_locals.push(Types::String(local.clone()));
-                }
-                // Define a new function in the VM
-                let func = Function {
-                    name: Some(name.to_string()),
-                    args: args.to_vec(),
-                    prog: func_prog.to_vec(),
-                };
-                functions.push(func);
+            OpCode::DefineFunc(name, args, func_prog) => {
+                // Top-level functions are globals like any other.
+                let new_func = Function::new(name.to_string(), args.to_vec(), func_prog.clone());
+                globals.insert(name.clone(), Types::Function(Rc::new(new_func)));
                 pc += 1;
             }
-            OpCode::Patch => {
-                unreachable!("Unabled to patch back value");
+            OpCode::Pop => {
+                pop(&mut stack, span)?;
+                pc += 1;
             }
         }
     }
     Ok(stack)
 }
 
-pub fn run(ast: Ast, lexer: &dyn NonStreamingLexer<DefaultLexeme<u32>, u32>) -> Vec<Types> {
-    let prog = compiler(ast, lexer);
-    let mut locals = Vec::new();
-    let mut functions: Vec<Function> = Vec::new();
-    let res = vm(prog.unwrap(), &mut locals, &mut functions);
-    res.unwrap()
+pub fn run(prog: Bytecode) -> Result<Vec<Types>, RuntimeError> {
+    let main = Function {
+        name: None,
+        args: Vec::new(),
+        prog: Rc::new(prog),
+        upvalues: Vec::new(),
+    };
+    vm(main)
 }
@@ -1,4 +1,4 @@
-use std::{env, fs};
+use std::{env, fs, process};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -9,5 +9,7 @@ fn main() {
     let file_name = &args[1];
     let contents = fs::read_to_string(file_name).expect("Could not read file");
 
-    ukiyo::compile(contents);
+    if !ukiyo::compile(file_name, contents) {
+        process::exit(1);
+    }
 }
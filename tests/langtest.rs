@@ -7,8 +7,10 @@ static COMMENT_PREFIX: &str = "//";
 fn main() {
     LangTester::new()
         .test_dir("tests/files")
-        // Only use files named `*.rs` as test files.
-        .test_file_filter(|p| p.extension().unwrap().to_str().unwrap() == "ukiyo")
+        // Only use files named `*.ukiyo` as test files.
+        .test_path_filter(|p| {
+            p.is_file() && p.extension().and_then(|e| e.to_str()) == Some("ukiyo")
+        })
         // Extract the first sequence of commented line(s) as the tests.
         .test_extract(|p| {
             read_to_string(p)
@@ -27,7 +29,7 @@ fn main() {
         //     succeed, then the output binary is run.
         .test_cmds(move |p| {
             let mut runner = Command::new("target/debug/ukiyo");
-            runner.args(&[p.to_str().unwrap()]);
+            runner.args([p.to_str().unwrap()]);
             vec![("Run-time", runner)]
         })
         .run();